@@ -1,9 +1,12 @@
 #include "include/MapRenderer.hpp"
 #include "include/log.hpp"
+#include "map-explorer/src/map_renderer.rs.h"
 
 #include <iostream>
 #include <string>
+#include <algorithm>
 #include <memory>
+#include <set>
 
 #pragma clang diagnostic push
 #pragma clang diagnostic ignored "-Wdeprecated-declarations"
@@ -14,6 +17,9 @@
 #include <mapnik/datasource_cache.hpp>
 #include <mapnik/font_engine_freetype.hpp>
 #include <mapnik/cairo/cairo_renderer.hpp>
+#include <mapnik/agg_renderer.hpp>
+#include <mapnik/image.hpp>
+#include <mapnik/image_util.hpp>
 #include <mapnik/geometry.hpp>
 #include <mapnik/geometry/box2d.hpp>
 #include <mapnik/proj_transform.hpp>
@@ -42,11 +48,114 @@ MapRenderer::MapRenderer(uint32_t width, uint32_t height, fs::path map_def_file,
   this->map.set_height(this->height);
 };
 
+// Paint an image rendered by AGG on `cr`, replacing what was there
+static void paint_image(std::shared_ptr<cairo_t> cr, mapnik::image_rgba8& image) {
+  mapnik::premultiply_alpha(image);
+  int width = image.width();
+  int height = image.height();
+  cairo_surface_t* surface = cairo_image_surface_create(CAIRO_FORMAT_ARGB32, width, height);
+  cairo_surface_flush(surface);
+  unsigned char* data = cairo_image_surface_get_data(surface);
+  int stride = cairo_image_surface_get_stride(surface);
+  // AGG stores RGBA bytes, cairo ARGB in native endian words
+  for (int y = 0; y < height; y++) {
+    const uint8_t* src = reinterpret_cast<const uint8_t*>(image.get_row(y));
+    uint32_t* dst = reinterpret_cast<uint32_t*>(data + y * stride);
+    for (int x = 0; x < width; x++) {
+      const uint8_t* rgba = src + x * 4;
+      dst[x] = (uint32_t)rgba[3] << 24 | (uint32_t)rgba[0] << 16 | (uint32_t)rgba[1] << 8 | (uint32_t)rgba[2];
+    }
+  }
+  cairo_surface_mark_dirty(surface);
+
+  cairo_t* dst = cr.get();
+  cairo_save(dst);
+  cairo_set_source_surface(dst, surface, 0, 0);
+  cairo_set_operator(dst, CAIRO_OPERATOR_SOURCE);
+  cairo_paint(dst);
+  cairo_restore(dst);
+  cairo_surface_destroy(surface);
+}
+
 void MapRenderer::render(void) {
+  if (this->agg) {
+    mapnik::image_rgba8 image(this->map.width(), this->map.height());
+    auto renderer = mapnik::agg_renderer<mapnik::image_rgba8>(this->map, image);
+    renderer.apply();
+    paint_image(this->cairo, image);
+    return;
+  }
   auto renderer = mapnik::cairo_renderer<std::shared_ptr<cairo_t>>(this->map, this->cairo /* scale, offset_x, offset_y */);
   renderer.apply();
 }
 
+bool MapRenderer::render_cancellable(const CancelToken& cancel) {
+  return this->render_layers(this->cairo, 1.0, cancel);
+}
+
+bool MapRenderer::render_preview(const CancelToken& cancel, uint32_t divisor) {
+  uint32_t preview_width = std::max(1u, this->width / divisor);
+  uint32_t preview_height = std::max(1u, this->height / divisor);
+  cairo_surface_t* surface = cairo_image_surface_create(CAIRO_FORMAT_ARGB32, preview_width, preview_height);
+  std::shared_ptr<cairo_t> cr(cairo_create(surface), cairo_destroy);
+
+  // Same extent with fewer pixels, symbols and labels shrink along so they look the same when scaled up
+  mapnik::box2d<double> extent = this->map.get_current_extent();
+  this->map.resize(preview_width, preview_height);
+  this->map.zoom_to_box(extent);
+  bool completed = this->render_layers(cr, 1.0 / divisor, cancel);
+  this->map.resize(this->width, this->height);
+  this->map.zoom_to_box(extent);
+
+  if (completed) {
+    cairo_t* dst = this->cairo.get();
+    cairo_save(dst);
+    cairo_scale(dst, (double)this->width / preview_width, (double)this->height / preview_height);
+    cairo_set_source_surface(dst, surface, 0, 0);
+    cairo_pattern_set_filter(cairo_get_source(dst), CAIRO_FILTER_BILINEAR);
+    cairo_set_operator(dst, CAIRO_OPERATOR_SOURCE);
+    cairo_paint(dst);
+    cairo_restore(dst);
+  }
+
+  cr.reset();
+  cairo_surface_destroy(surface);
+  return completed;
+}
+
+bool MapRenderer::render_layers(std::shared_ptr<cairo_t> cr, double scale_factor, const CancelToken& cancel) {
+  if (this->agg) {
+    return this->render_layers_agg(cr, scale_factor, cancel);
+  }
+  // The background is painted when the renderer is created, the layers share its label collision detector
+  auto renderer = mapnik::cairo_renderer<std::shared_ptr<cairo_t>>(this->map, cr, scale_factor);
+  for (const mapnik::layer& layer : this->map.layers()) {
+    if (cancel.is_cancelled()) {
+      return false;
+    }
+    std::set<std::string> names;
+    renderer.apply(layer, names);
+  }
+  return true;
+}
+
+bool MapRenderer::render_layers_agg(std::shared_ptr<cairo_t> cr, double scale_factor, const CancelToken& cancel) {
+  mapnik::image_rgba8 image(this->map.width(), this->map.height());
+  // Paints the background like the cairo renderer
+  auto renderer = mapnik::agg_renderer<mapnik::image_rgba8>(this->map, image, scale_factor);
+  for (const mapnik::layer& layer : this->map.layers()) {
+    if (cancel.is_cancelled()) {
+      return false;
+    }
+    // Every layer leaves the image demultiplied, AGG draws on premultiplied pixels
+    mapnik::premultiply_alpha(image);
+    std::set<std::string> names;
+    renderer.apply(layer, names);
+  }
+  paint_image(cr, image);
+  return true;
+}
+
 // Controls //
 
 void MapRenderer::move(double x, double y) {
@@ -84,6 +193,17 @@ void MapRenderer::resize(uint32_t width, uint32_t height) {
   this->height = height;
 }
 
+void MapRenderer::update_styles(const std::string& styles_def, const std::string& base_path) {
+  INFO << "Updating styles..." << std::endl;
+  mapnik::Map styles_map = mapnik::Map(this->width, this->height);
+  mapnik::load_map_string(styles_map, styles_def, false, base_path);
+  this->map.styles() = styles_map.styles();
+}
+
 void MapRenderer::set_cairo(std::shared_ptr<cairo_t> cr) noexcept {
   this->cairo = cr;
 }
+
+void MapRenderer::set_agg(bool agg) noexcept {
+  this->agg = agg;
+}
@@ -2,6 +2,7 @@
 #include "include/log.hpp"
 #include "mapnik/projection.hpp"
 #include <memory>
+#include <stdexcept>
 
 static bool is_mapnik_setup = false;
 
@@ -46,6 +47,14 @@ std::shared_ptr<mapnik::geometry::point<double>> new_point_double(double x, doub
   return std::make_shared<mapnik::geometry::point<double>>(x, y);
 }
 
+double point_get_x(const point_double& p) {
+  return p.x;
+}
+
+double point_get_y(const point_double& p) {
+  return p.y;
+}
+
 std::shared_ptr<mapnik::projection> new_projection(const std::string& srs) {
   return std::make_shared<mapnik::projection>(srs);
 }
@@ -54,6 +63,30 @@ std::unique_ptr<std::string> projection_definition(std::shared_ptr<mapnik::proje
   return std::make_unique<std::string>(proj->definition());
 }
 
+bool projection_is_geographic(const mapnik::projection& proj) {
+  return proj.is_geographic();
+}
+
+std::shared_ptr<mapnik::proj_transform> new_proj_transform(const mapnik::projection& projsrc, const mapnik::projection& projdst) {
+  return std::make_shared<mapnik::proj_transform>(projsrc, projdst);
+}
+
+std::shared_ptr<point_double> proj_transform_forward(const mapnik::proj_transform& trans, const point_double& p) {
+  point_double transformed = p;
+  if (!trans.forward(transformed)) {
+    throw std::runtime_error("Couldn't transform point to " + trans.definition());
+  }
+  return std::make_shared<point_double>(transformed);
+}
+
+std::shared_ptr<point_double> proj_transform_backward(const mapnik::proj_transform& trans, const point_double& p) {
+  point_double transformed = p;
+  if (!trans.backward(transformed)) {
+    throw std::runtime_error("Couldn't transform point from " + trans.definition());
+  }
+  return std::make_shared<point_double>(transformed);
+}
+
 std::shared_ptr<mapnik::box2d<double>> make_center_box(
    const mapnik::geometry::point<double>& center,
    const mapnik::projection& projsrc,
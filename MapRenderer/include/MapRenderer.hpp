@@ -13,18 +13,28 @@
 #include <mapnik/datasource_cache.hpp>
 #include <mapnik/font_engine_freetype.hpp>
 #include <mapnik/cairo/cairo_renderer.hpp>
+#include <mapnik/agg_renderer.hpp>
+#include <mapnik/image.hpp>
 #include <mapnik/geometry/box2d.hpp>
 #include <mapbox/geometry/point.hpp>
 #pragma clang diagnostic pop
 
 namespace fs = std::filesystem;
 
+// Defined in Rust, see map_renderer.rs
+struct CancelToken;
+
 class MapRenderer {
   private:
   uint32_t width;
   uint32_t height;
 
   std::shared_ptr<cairo_t> cairo;
+  /// Render with AGG into an image that is painted on the cairo context, instead of with cairo directly
+  bool agg = false;
+
+  bool render_layers(std::shared_ptr<cairo_t> cr, double scale_factor, const CancelToken& cancel);
+  bool render_layers_agg(std::shared_ptr<cairo_t> cr, double scale_factor, const CancelToken& cancel);
 
   public:
   mapnik::Map map;
@@ -38,7 +48,16 @@ class MapRenderer {
   void zoom_to_box(const mapnik::box2d<double>&);
 
   void resize(uint32_t width, uint32_t height);
+
+  /// Replace the styles of the map with the ones defined in `styles_def`, keeping the layers and their datasources
+  void update_styles(const std::string& styles_def, const std::string& base_path);
   void set_cairo(std::shared_ptr<cairo_t>) noexcept;
+  /// Render with Mapnik's AGG renderer, like most tile servers, or with its cairo renderer
+  void set_agg(bool agg) noexcept;
 
   void render(void);
+  /// Render layer by layer, returns false when `cancel` was cancelled before all layers were rendered
+  bool render_cancellable(const CancelToken& cancel);
+  /// Render at 1/`divisor` of the width and height, scaled up to the full size. Cancellable like `render_cancellable`.
+  bool render_preview(const CancelToken& cancel, uint32_t divisor);
 };
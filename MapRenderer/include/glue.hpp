@@ -8,6 +8,7 @@
 #include <Poco/Pipe.h>
 #include <Poco/PipeStream.h>
 #include <mapnik/projection.hpp>
+#include <mapnik/proj_transform.hpp>
 
 void setup_mapnik(const std::string& datasources_dir, const std::string& fonts_dir);
 
@@ -24,9 +25,20 @@ typedef mapnik::geometry::point<double> point_double;
 
 std::shared_ptr<mapnik::geometry::point<double>> new_point_double(double center_x, double center_y);
 
+double point_get_x(const point_double& p);
+double point_get_y(const point_double& p);
+
 std::shared_ptr<mapnik::projection> new_projection(const std::string& srs);
 
 std::unique_ptr<std::string> projection_definition(std::shared_ptr<mapnik::projection> proj);
+bool projection_is_geographic(const mapnik::projection& proj);
+
+std::shared_ptr<mapnik::proj_transform> new_proj_transform(const mapnik::projection& projsrc, const mapnik::projection& projdst);
+
+/// Transform a point from the source to the destination projection, throws when the point can't be transformed
+std::shared_ptr<point_double> proj_transform_forward(const mapnik::proj_transform& trans, const point_double& p);
+/// Transform a point from the destination to the source projection, throws when the point can't be transformed
+std::shared_ptr<point_double> proj_transform_backward(const mapnik::proj_transform& trans, const point_double& p);
 
 std::shared_ptr<mapnik::box2d<double>> make_center_box(
   const mapnik::geometry::point<double>& center,
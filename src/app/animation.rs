@@ -0,0 +1,69 @@
+use std::time::{Duration, Instant};
+
+use crate::Point;
+use super::controls::Controls;
+
+/// The part of the map that is on screen, in the output projection
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct DisplayedView {
+    pub(crate) center: Point<f64>,
+    pub(crate) units_per_pixel: f64,
+}
+
+impl DisplayedView {
+    pub(crate) fn of(controls: &Controls) -> anyhow::Result<Self> {
+        Ok(Self {
+            center: controls.projected_center()?,
+            units_per_pixel: controls.units_per_pixel_scale,
+        })
+    }
+}
+
+/// Moves the view on screen smoothly to a new view.
+///
+/// The renderer starts on the new view right away. Meanwhile the last frame is moved and
+/// scaled on the GPU.
+#[derive(Debug, Clone)]
+pub(crate) struct ViewAnimation {
+    from: DisplayedView,
+    to: DisplayedView,
+    start: Instant,
+}
+
+impl ViewAnimation {
+    const DURATION: Duration = Duration::from_millis(200);
+
+    pub(crate) fn new(from: DisplayedView, to: DisplayedView) -> Self {
+        Self { from, to, start: Instant::now() }
+    }
+
+    pub(crate) fn finished(&self, now: Instant) -> bool {
+        now - self.start >= Self::DURATION
+    }
+
+    /// The view on screen at `now`
+    pub(crate) fn at(&self, now: Instant) -> DisplayedView {
+        let t = ((now - self.start).as_secs_f64() / Self::DURATION.as_secs_f64()).clamp(0.0, 1.0);
+        // Ease out, so the animation responds immediately
+        let t = 1.0 - (1.0 - t).powi(3);
+
+        // Zooming is perceived linearly on a logarithmic scale
+        let units_per_pixel = self.from.units_per_pixel * (self.to.units_per_pixel / self.from.units_per_pixel).powf(t);
+        // Moving the center in step with the scale keeps the point that stays in place during
+        // a zoom (e.g. the point under the cursor) still during the animation
+        let scale_change = self.to.units_per_pixel - self.from.units_per_pixel;
+        let s = if scale_change.abs() > self.from.units_per_pixel * 1e-9 {
+            (units_per_pixel - self.from.units_per_pixel) / scale_change
+        } else {
+            t
+        };
+
+        DisplayedView {
+            center: Point::new(
+                self.from.center.x + (self.to.center.x - self.from.center.x) * s,
+                self.from.center.y + (self.to.center.y - self.from.center.y) * s,
+            ),
+            units_per_pixel,
+        }
+    }
+}
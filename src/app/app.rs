@@ -1,57 +1,135 @@
 use std::fs::File;
 use std::io::BufWriter;
 use std::path::PathBuf;
-use std::sync::Arc;
+use std::sync::{mpsc, Arc};
 use std::time;
 
 use serde::Serialize;
 use winit::application::ApplicationHandler;
 use winit::dpi;
-use winit::event::{MouseButton, WindowEvent};
-use winit::event_loop::ActiveEventLoop;
+use winit::event::{ElementState, MouseButton, MouseScrollDelta, StartCause, WindowEvent};
+use winit::event_loop::{ActiveEventLoop, ControlFlow, EventLoopProxy};
 use log::*;
 
+use crate::app::compare::CompareMode;
+use crate::app::config::Config;
 use crate::app::controls::Controls;
+use crate::app::history::History;
+use crate::app::status_bar::StatusBar;
 use crate::file_watcher::FileWatcher;
+use crate::git::HeadCheckout;
+use crate::{RenderBackend, Stylesheet};
 
+use super::map_layer::*;
 use super::window::*;
+use super::AppEvent;
+
+/// What the map is compared with
+pub enum CompareWith {
+    Stylesheet(Stylesheet),
+    /// The stylesheet as committed at HEAD, checked out again after every commit or checkout
+    Head(HeadCheckout),
+}
+
+/// What the viewer shows and where it keeps its files
+pub struct MapExplorerOptions {
+    pub map_def: Stylesheet,
+    /// New versions of an in-memory stylesheet, e.g. read from stdin
+    pub map_def_updates: Option<mpsc::Receiver<String>>,
+    pub compare: Option<CompareWith>,
+    pub basepath: PathBuf,
+    pub inifile: PathBuf,
+    pub cachefile: PathBuf,
+    pub configfile: PathBuf,
+    /// Overrides `render.backend` from the config for this session
+    pub backend: Option<RenderBackend>,
+    /// Overrides `render.workers` from the config for this session
+    pub workers: Option<usize>,
+}
 
 pub struct MapExplorer {
     window: Option<MapExplorerWindow>,
     w: usize,
     h: usize,
-    map_def_file: PathBuf,
-    map_def_watcher: FileWatcher,
-    basepath: PathBuf,
-    inifile: PathBuf,
-    cachefile: PathBuf,
+    map_def: Stylesheet,
+    map_def_watcher: Option<FileWatcher>,
+    /// New versions of an in-memory stylesheet, e.g. read from stdin
+    map_def_updates: Option<mpsc::Receiver<String>>,
+    compare_map_def: Option<Stylesheet>,
+    compare_map_def_watcher: Option<FileWatcher>,
+    compare_head: Option<HeadCheckout>,
+    /// Notices when HEAD moves, to check out `compare_head` again
+    head_watcher: Option<FileWatcher>,
+    paths: AppPaths,
+    config: Config,
+    /// Wakes up the event loop from other threads
+    proxy: EventLoopProxy<AppEvent>,
 }
 
 impl MapExplorer {
-    pub fn new(
-        w: usize, h: usize,
-        map_def_file: impl Into<PathBuf>, basepath: impl Into<PathBuf>,
-        inifile: impl Into<PathBuf>,
-        cachefile: impl Into<PathBuf>
-    ) -> anyhow::Result<MapExplorer> {
-        let map_def_file = map_def_file.into();
-        let map_def_watcher = FileWatcher::new(&map_def_file)?;
+    pub fn new(w: usize, h: usize, options: MapExplorerOptions, proxy: EventLoopProxy<AppEvent>) -> anyhow::Result<MapExplorer> {
+        let MapExplorerOptions {
+            map_def,
+            map_def_updates,
+            compare,
+            basepath,
+            inifile,
+            cachefile,
+            configfile,
+            backend,
+            workers,
+        } = options;
+        let mut config = Config::load(configfile)?;
+        // The command line chooses for this session only
+        if let Some(backend) = backend {
+            config.render.backend = backend;
+        }
+        if workers.is_some() {
+            config.render.workers = workers;
+        }
+        let watch = |path: &PathBuf| {
+            let proxy = proxy.clone();
+            FileWatcher::new(path, move || { _ = proxy.send_event(AppEvent::StylesheetChanged); })
+        };
+        let map_def_watcher = match &map_def {
+            Stylesheet::File(path) => Some(watch(path)?),
+            Stylesheet::Xml(_) => None,
+        };
+        let (compare_map_def, compare_head) = match compare {
+            Some(CompareWith::Stylesheet(stylesheet)) => (Some(stylesheet), None),
+            Some(CompareWith::Head(head)) => (Some(Stylesheet::File(head.path().to_path_buf())), Some(head)),
+            None => (None, None),
+        };
+        // The checkout at HEAD is replaced as a whole, HEAD itself is watched instead
+        let compare_map_def_watcher = match (&compare_map_def, &compare_head) {
+            (Some(Stylesheet::File(path)), None) => Some(watch(path)?),
+            _ => None,
+        };
+        let head_watcher = match &compare_head {
+            Some(head) => Some(watch(&head.head_file()?)?),
+            None => None,
+        };
         Ok(MapExplorer {
             window: None,
             w, h,
-            map_def_file,
+            map_def,
             map_def_watcher,
-            basepath: basepath.into(),
-            inifile: inifile.into(),
-            cachefile: cachefile.into(),
+            map_def_updates,
+            compare_map_def,
+            compare_map_def_watcher,
+            compare_head,
+            head_watcher,
+            paths: AppPaths { basepath, inifile, cachefile },
+            config,
+            proxy,
         })
     }
 }
 
-impl ApplicationHandler for MapExplorer {
+impl ApplicationHandler<AppEvent> for MapExplorer {
     fn resumed(&mut self, event_loop: &ActiveEventLoop) {
         // TODO: handle unwrap
-        self.window = Some(pollster::block_on(MapExplorerWindow::new(self.w, self.h, event_loop, &self.map_def_file, &self.basepath, &self.inifile, &self.cachefile)).unwrap());
+        self.window = Some(pollster::block_on(MapExplorerWindow::new((self.w, self.h), event_loop, &self.map_def, self.compare_map_def.as_ref(), &self.paths, &self.config, &self.proxy)).unwrap());
     }
 
     fn window_event(
@@ -65,26 +143,70 @@ impl ApplicationHandler for MapExplorer {
         {
             let imgui = &mut window.imgui;
 
-            imgui.platform.handle_event::<()>(
+            imgui.platform.handle_event::<AppEvent>(
                 imgui.context.io_mut(),
                 &window.window,
                 &winit::event::Event::WindowEvent { window_id, event: event.clone() });
         }
 
+        // Input changes what is shown, imgui needs a few frames to follow
+        if !matches!(event, WindowEvent::RedrawRequested) {
+            window.settle_frames = MapExplorerWindow::SETTLE_FRAMES;
+            window.window.request_redraw();
+        }
+
         match &event {
             WindowEvent::Resized(new_size) => {
-                window.resize_map(new_size.width, new_size.height).unwrap();
+                match window.resize_window(*new_size) {
+                    Ok(()) => {},
+                    Err(ResizeMapResult::Size0) => {}, // minimized
+                    Err(ResizeMapResult::SizeTooBig) => warn!("The window is too big to render the map at {}x", window.render_scale),
+                    Err(ResizeMapResult::Error(err)) => error!("{}", err),
+                }
             }
             WindowEvent::CloseRequested => event_loop.exit(),
             WindowEvent::RedrawRequested => {
-                if self.map_def_watcher.changed().unwrap() {
+                let mut map_def_changed = match &mut self.map_def_watcher {
+                    Some(watcher) => watcher.changed().unwrap(),
+                    None => false,
+                };
+                if let Some(updates) = &self.map_def_updates {
+                    // Only the most recent stylesheet is of interest
+                    while let Ok(xml) = updates.try_recv() {
+                        self.map_def = Stylesheet::Xml(xml);
+                        window.map.map_def = self.map_def.clone();
+                        map_def_changed = true;
+                    }
+                }
+                if map_def_changed {
                     match window.reload_map() {
                         Ok(()) => {},
                         Err(err) => error!("{}", err),
                     }
                 }
+                if let Some(watcher) = &mut self.compare_map_def_watcher
+                    && watcher.changed().unwrap()
+                {
+                    match window.reload_compare_map() {
+                        Ok(()) => {},
+                        Err(err) => error!("{}", err),
+                    }
+                }
+                if let Some(watcher) = &mut self.head_watcher
+                    && watcher.changed().unwrap()
+                    && let Some(head) = &self.compare_head
+                {
+                    match head.update().and_then(|()| window.reload_compare_map()) {
+                        Ok(()) => {},
+                        Err(err) => error!("{}", err),
+                    }
+                }
 
                 let mut should_reload = false;
+                let mut view_changed = false;
+                let mut minimap_center = None;
+                let mut minimap_released = false;
+                let mut go_to = None;
 
                 let frame = match window.surface.get_current_texture() {
                     Ok(frame) => frame,
@@ -96,10 +218,10 @@ impl ApplicationHandler for MapExplorer {
 
                 // Map
                 window.update_buffer().unwrap();
+                window.update_cursor_readout();
 
                 // UI
-                #[allow(unused_mut)] // TODO (see next TODO)
-                let mut resize_window: Option<(u32, u32)> = None;
+                let mut render_scale = None;
                 let imgui = &mut window.imgui;
                 let now = time::Instant::now();
                 imgui.context
@@ -113,44 +235,107 @@ impl ApplicationHandler for MapExplorer {
 
                 let ui = imgui.context.frame();
 
-                {
+                if window.show_controls {
                     let win = ui.window("Controls");
                     win
                         .size([300.0, 100.0], imgui::Condition::FirstUseEver)
                         .build(|| {
-                            let mut changed = false;
-                            ui.input_float("x", &mut window.controls.center_x).build();
-                            ui.input_float("y", &mut window.controls.center_y).build();
-                            changed |= ui.input_float("units per pixel", &mut window.controls.units_per_pixel_scale).build();
-                            // TODO:
-                            // let mut scale = (window.controls.map_width as f32) / (window.window.inner_size().width as f32);
-                            // changed |= ui.input_float("render scale", &mut scale).build();
-                            // if scale != (window.controls.map_width as f32) / (window.window.inner_size().width as f32) {
-                            //     resize_window = Some((((window.window.inner_size().width as f32) * scale) as u32, ((window.window.inner_size().height as f32) * scale) as u32));
-                            // }
+                            go_to = window.search.draw(ui);
+                            ui.separator();
+
+                            view_changed |= ui.input_scalar("x", &mut window.controls.center_x).display_format("%.10g").build();
+                            view_changed |= ui.input_scalar("y", &mut window.controls.center_y).display_format("%.10g").build();
+                            view_changed |= ui.input_scalar("units per pixel", &mut window.controls.units_per_pixel_scale).display_format("%.6g").build();
+                            let mut scale = window.render_scale;
+                            if ui.slider("render scale", MapExplorerWindow::MIN_RENDER_SCALE, MapExplorerWindow::MAX_RENDER_SCALE, &mut scale) {
+                                render_scale = Some(scale);
+                            }
                             match window.controls.updating_input_projection(|srs| {
                                 ui.input_text("input projection", srs).build()
                             }) {
-                                Ok(c) => changed |= c,
+                                Ok(c) => view_changed |= c,
                                 Err(err) => error!("{}", err),
                             }
 
                             match window.controls.updating_output_projection(|srs| {
                                 ui.input_text("output projection", srs).build()
                             }) {
-                                Ok(c) => changed |= c,
+                                Ok(c) => view_changed |= c,
                                 Err(err) => error!("{}", err),
                             }
 
                             should_reload = ui.button("reload");
+                            if let Some(changed_pixels) = window.reload_diff.changed_pixels {
+                                let total = window.reload_diff.viewport_pixels;
+                                ui.same_line();
+                                ui.text(format!("{} pixels changed ({:.2}%)", changed_pixels, 100.0 * changed_pixels as f64 / total.max(1) as f64));
+                            }
+                            ui.text_disabled(format!("cancelled renders: {}", window.map.cancelled_renders()));
 
-                            if changed {
-                                window.static_user_data = Arc::new(UserDataStatic::new(&window.controls));
-                                window.ud_sender.send((window.controls.center_x, window.controls.center_y, window.static_user_data.clone())).unwrap();
+                            if window.minimap.is_some() {
+                                ui.checkbox("minimap", &mut window.show_minimap);
                             }
+
+                            if window.compare_map.is_some() {
+                                ui.separator();
+                                let compare = &mut window.compare;
+                                let mut mode = CompareMode::ALL.iter().position(|mode| *mode == compare.mode).unwrap();
+                                if ui.combo_simple_string("compare", &mut mode, &CompareMode::ALL.map(|mode| mode.name())) {
+                                    compare.mode = CompareMode::ALL[mode];
+                                }
+                                match compare.mode {
+                                    CompareMode::SideBySide | CompareMode::Difference => {},
+                                    CompareMode::Swipe => {
+                                        ui.slider("divider", 0.0, 1.0, &mut compare.swipe);
+                                    },
+                                    CompareMode::Blink => {
+                                        if ui.button("toggle") {
+                                            compare.toggle_blink();
+                                        }
+                                        ui.same_line();
+                                        ui.checkbox("auto", &mut compare.auto_blink);
+                                        if compare.auto_blink {
+                                            ui.slider("interval (s)", 0.1, 5.0, &mut compare.blink_interval);
+                                        }
+                                    },
+                                }
+                            }
+
                         });
                 }
 
+                window.status_bar.draw(ui, &window.controls, window.map.is_preview());
+
+                if let (Some(minimap), true) = (&mut window.minimap, window.show_minimap) {
+                    (minimap_center, minimap_released) = minimap.draw(ui, &window.controls, StatusBar::height(ui));
+                }
+
+                // Swipe divider
+                if window.compare_map.is_some() && window.compare.mode == CompareMode::Swipe {
+                    let [display_w, display_h] = ui.io().display_size;
+                    let x = window.compare.swipe * display_w;
+                    let color = if window.compare.dragging_swipe { [1.0, 0.6, 0.0, 1.0] } else { [0.2, 0.2, 0.2, 0.9] };
+                    let draw_list = ui.get_background_draw_list();
+                    draw_list.add_line([x, 0.0], [x, display_h], color).thickness(2.0).build();
+                    draw_list.add_circle([x, display_h / 2.0], 8.0, color).filled(true).build();
+                }
+
+                // Zoom box, Alt zooms out
+                if let Some(start) = window.zoom_box_start {
+                    let size = window.window.inner_size();
+                    let [display_w, display_h] = ui.io().display_size;
+                    let to_display = |position: dpi::PhysicalPosition<f64>| [
+                        (position.x / size.width.max(1) as f64) as f32 * display_w,
+                        (position.y / size.height.max(1) as f64) as f32 * display_h,
+                    ];
+                    let color = if window.modifiers.alt_key() { [1.0, 0.5, 0.0, 1.0] } else { [0.0, 0.5, 1.0, 1.0] };
+                    let fill = [color[0], color[1], color[2], 0.15];
+                    let draw_list = ui.get_background_draw_list();
+                    let (start, end) = (to_display(start), to_display(window.cursor_position));
+                    draw_list.add_rect(start, end, fill).filled(true).build();
+                    draw_list.add_rect(start, end, color).thickness(1.5).build();
+                }
+
                 // Finish rendering
                 let mut encoder: wgpu::CommandEncoder = window.device
                     .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
@@ -177,8 +362,14 @@ impl ApplicationHandler for MapExplorer {
 
                 // map
                 rpass.set_pipeline(&window.map_pipeline);
-                rpass.set_bind_group(0, &window.map_bind_group, &[]);
+                rpass.set_bind_group(0, &window.map.bind_group, &[]);
                 rpass.set_bind_group(1, &window.map_delta_bind_group, &[]);
+                let compare_bind_group = match &window.compare_map {
+                    Some(compare_map) => &compare_map.bind_group,
+                    None => &window.map.bind_group,
+                };
+                rpass.set_bind_group(2, compare_bind_group, &[]);
+                rpass.set_bind_group(3, &window.reload_diff.bind_group, &[]);
                 rpass.draw(0..6, 0..1);
 
                 // ui
@@ -201,8 +392,30 @@ impl ApplicationHandler for MapExplorer {
 
                 frame.present();
 
-                if let Some(new_size) = resize_window {
-                    match window.resize_map(new_size.0, new_size.1) {
+                if let Some(location) = go_to
+                    && let Err(err) = window.go_to(&location)
+                {
+                    error!("Couldn't go to {}: {}", location.name, err);
+                }
+
+                if let Some(center) = minimap_center
+                    && let Err(err) = window.center_on(center)
+                {
+                    error!("Couldn't move to the minimap position: {}", err);
+                }
+                if minimap_released {
+                    window.commit_view(false);
+                }
+
+                if view_changed {
+                    window.static_user_data = Arc::new(UserDataStatic::new(&window.controls, window.overscan));
+                    window.send_user_data().unwrap();
+                    // Every typed character changes the view, they are merged into one entry
+                    window.commit_view(true);
+                }
+
+                if let Some(render_scale) = render_scale {
+                    match window.set_render_scale(render_scale) {
                         Ok(()) => {},
                         Err(err) => match err {
                             ResizeMapResult::Size0 | ResizeMapResult::SizeTooBig => warn!("{:?}", err),
@@ -227,20 +440,88 @@ impl ApplicationHandler for MapExplorer {
             },
             WindowEvent::MouseInput { state, button, .. } if *button == MouseButton::Left => {
                 match state {
-                    winit::event::ElementState::Pressed if !unsafe { imgui_sys::igIsWindowHovered(imgui_sys::ImGuiHoveredFlags_AnyWindow as i32) }
-                        => window.mouse_pressed = true,
-                    winit::event::ElementState::Released => window.mouse_pressed = false,
+                    winit::event::ElementState::Pressed if !unsafe { imgui_sys::igIsWindowHovered(imgui_sys::ImGuiHoveredFlags_AnyWindow as i32) } => {
+                        if window.modifiers.shift_key() {
+                            window.zoom_box_start = Some(window.cursor_position);
+                        } else if window.compare_map.is_some() && window.compare.grabs_divider(window.cursor_position.x, window.window.inner_size().width) {
+                            window.compare.dragging_swipe = true;
+                        } else if let Err(err) = window.start_pan() {
+                            error!("Couldn't start panning: {}", err);
+                        }
+                    },
+                    winit::event::ElementState::Released => {
+                        if let Err(err) = window.finish_zoom_box(window.modifiers.alt_key()) {
+                            error!("Couldn't zoom to box: {}", err);
+                        }
+                        window.finish_pan();
+                        window.compare.dragging_swipe = false;
+                    },
                     _ => {}
                 }
             }
+            WindowEvent::MouseInput { state: ElementState::Pressed, button: button @ (MouseButton::Back | MouseButton::Forward), .. } => {
+                let result = match button {
+                    MouseButton::Back => window.go_back(),
+                    _ => window.go_forward(),
+                };
+                if let Err(err) = result {
+                    error!("Couldn't navigate: {}", err);
+                }
+            }
+            WindowEvent::MouseWheel { delta, .. } if !unsafe { imgui_sys::igIsWindowHovered(imgui_sys::ImGuiHoveredFlags_AnyWindow as i32) } => {
+                // Scrolling up zooms in, by ZOOM_STEP per notch of a mouse wheel
+                const PIXELS_PER_NOTCH: f64 = 100.0;
+                let notches = match delta {
+                    MouseScrollDelta::LineDelta(_, y) => *y as f64,
+                    MouseScrollDelta::PixelDelta(position) => position.y / PIXELS_PER_NOTCH,
+                };
+                if notches != 0.0 {
+                    match window.zoom_at_cursor(super::ZOOM_STEP.powf(-notches)) {
+                        Ok(()) => {},
+                        Err(err) => error!("Couldn't zoom: {}", err),
+                    }
+                }
+            }
+            WindowEvent::ModifiersChanged(modifiers) => {
+                window.modifiers = modifiers.state();
+            }
+            // Keys typed into a text field are not shortcuts
+            WindowEvent::KeyboardInput { event, .. } if event.state == ElementState::Pressed && !window.imgui.context.io().want_text_input => {
+                if let Some(action) = self.config.keybindings.action(event, window.modifiers) {
+                    if event.repeat && !action.repeats() { return }
+                    match window.run_action(action, &self.config) {
+                        Ok(()) => {},
+                        Err(err) => error!("{:?} failed: {}", action, err),
+                    }
+                }
+            }
+            WindowEvent::CursorMoved { position, .. } => {
+                window.cursor_position = *position;
+                if window.compare.dragging_swipe {
+                    let width = window.window.inner_size().width.max(1) as f64;
+                    window.compare.swipe = (position.x / width).clamp(0.0, 1.0) as f32;
+                }
+                if let Err(err) = window.pan_to_cursor() {
+                    error!("Couldn't pan: {}", err);
+                }
+            }
             _ => {},
         }
     }
 
-    fn user_event(&mut self, _: &ActiveEventLoop, event: ()) {
-        let window = self.window.as_mut().unwrap();
+    fn new_events(&mut self, _: &ActiveEventLoop, cause: StartCause) {
+        // The deadline set in `about_to_wait` passed
+        if let (StartCause::ResumeTimeReached { .. }, Some(window)) = (cause, &self.window) {
+            window.window.request_redraw();
+        }
+    }
+
+    fn user_event(&mut self, _: &ActiveEventLoop, event: AppEvent) {
+        let Some(window) = self.window.as_mut() else { return };
+        // New frames and stylesheets are picked up when drawing
+        window.window.request_redraw();
         let imgui = &mut window.imgui;
-        imgui.platform.handle_event::<()>(
+        imgui.platform.handle_event::<AppEvent>(
             imgui.context.io_mut(),
             &window.window,
             &winit::event::Event::UserEvent(event),
@@ -255,28 +536,28 @@ impl ApplicationHandler for MapExplorer {
     ) {
         let window = self.window.as_mut().unwrap();
 
-        match &event {
-            &winit::event::DeviceEvent::MouseMotion { delta } if window.mouse_pressed == true => {
-                window.controls.center_x += (delta.0 as f32) * window.controls.units_per_pixel_scale * super::TEMP_OFFSET;
-                window.controls.center_y += (delta.1 as f32) * window.controls.units_per_pixel_scale * super::TEMP_OFFSET;
-                window.ud_sender.send((window.controls.center_x, window.controls.center_y, window.static_user_data.clone())).unwrap();
-            },
-            _ => {}
-        }
-
         let imgui = &mut window.imgui;
-        imgui.platform.handle_event::<()>(
+        imgui.platform.handle_event::<AppEvent>(
             imgui.context.io_mut(),
             &window.window,
             &winit::event::Event::DeviceEvent { device_id, event },
         );
     }
 
-    fn about_to_wait(&mut self, _: &ActiveEventLoop) {
+    fn about_to_wait(&mut self, event_loop: &ActiveEventLoop) {
         let window = self.window.as_mut().unwrap();
+        let now = time::Instant::now();
+        // Sleep until the next event, unless something on screen is moving
+        match window.next_redraw(now) {
+            Some(at) if at <= now => {
+                window.window.request_redraw();
+                event_loop.set_control_flow(ControlFlow::Wait);
+            },
+            Some(at) => event_loop.set_control_flow(ControlFlow::WaitUntil(at)),
+            None => event_loop.set_control_flow(ControlFlow::Wait),
+        }
         let imgui = &mut window.imgui;
-        window.window.request_redraw();
-        imgui.platform.handle_event::<()>(
+        imgui.platform.handle_event::<AppEvent>(
             imgui.context.io_mut(),
             &window.window,
             &winit::event::Event::AboutToWait
@@ -299,13 +580,14 @@ impl Drop for MapExplorer {
         struct MapExplorerCache<'a> {
             controls: &'a Controls,
             window: Option<dpi::PhysicalPosition<i32>>,
+            history: &'a History,
         }
 
-        let f = File::create(&self.cachefile).unwrap();
+        let f = File::create(&self.paths.cachefile).unwrap();
         let w = BufWriter::new(f);
-        let json = serde_json::to_writer(w, &MapExplorerCache { controls: &win.controls, window: pos });
+        let json = serde_json::to_writer(w, &MapExplorerCache { controls: &win.controls, window: pos, history: &win.history });
         if let Err(err) = json {
-            error!("Couldn't write cache to {}: {}", self.cachefile.display(), err);
+            error!("Couldn't write cache to {}: {}", self.paths.cachefile.display(), err);
         }
     }
 }
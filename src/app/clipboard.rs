@@ -0,0 +1,28 @@
+use log::*;
+
+/// Lets imgui use the system clipboard
+pub(crate) struct Clipboard(arboard::Clipboard);
+
+impl Clipboard {
+    pub(crate) fn new() -> Option<Self> {
+        match arboard::Clipboard::new() {
+            Ok(clipboard) => Some(Self(clipboard)),
+            Err(err) => {
+                warn!("System clipboard unavailable: {}", err);
+                None
+            },
+        }
+    }
+}
+
+impl imgui::ClipboardBackend for Clipboard {
+    fn get(&mut self) -> Option<String> {
+        self.0.get_text().ok()
+    }
+
+    fn set(&mut self, value: &str) {
+        if let Err(err) = self.0.set_text(value) {
+            error!("Couldn't copy to the clipboard: {}", err);
+        }
+    }
+}
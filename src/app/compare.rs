@@ -0,0 +1,91 @@
+use std::time::{Duration, Instant};
+
+/// How two stylesheets are shown when comparing them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CompareMode {
+    /// Both maps next to each other, each showing the middle of the view
+    SideBySide,
+    /// The compared map is shown right of a draggable divider
+    Swipe,
+    /// Alternate between both maps
+    Blink,
+    /// Highlight the pixels that differ between both maps
+    Difference,
+}
+
+impl CompareMode {
+    pub(crate) const ALL: [CompareMode; 4] = [CompareMode::SideBySide, CompareMode::Swipe, CompareMode::Blink, CompareMode::Difference];
+
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            CompareMode::SideBySide => "side by side",
+            CompareMode::Swipe => "swipe",
+            CompareMode::Blink => "blink",
+            CompareMode::Difference => "difference",
+        }
+    }
+}
+
+pub(crate) struct Compare {
+    pub(crate) mode: CompareMode,
+    /// Position of the swipe divider, from 0 (left) to 1 (right)
+    pub(crate) swipe: f32,
+    pub(crate) dragging_swipe: bool,
+    pub(crate) blink_show_compare: bool,
+    pub(crate) auto_blink: bool,
+    /// Seconds between blinks
+    pub(crate) blink_interval: f32,
+    last_blink: Instant,
+}
+
+impl Compare {
+    pub(crate) fn new() -> Self {
+        Self {
+            mode: CompareMode::Swipe,
+            swipe: 0.5,
+            dragging_swipe: false,
+            blink_show_compare: false,
+            auto_blink: false,
+            blink_interval: 0.75,
+            last_blink: Instant::now(),
+        }
+    }
+
+    pub(crate) fn toggle_blink(&mut self) {
+        self.blink_show_compare = !self.blink_show_compare;
+        self.last_blink = Instant::now();
+    }
+
+    /// Advance the automatic blink
+    pub(crate) fn update(&mut self, now: Instant) {
+        if self.mode == CompareMode::Blink && self.auto_blink
+            && now - self.last_blink >= Duration::from_secs_f32(self.blink_interval.max(0.05))
+        {
+            self.toggle_blink();
+        }
+    }
+
+    /// When the automatic blink switches maps next
+    pub(crate) fn next_blink(&self) -> Option<Instant> {
+        (self.mode == CompareMode::Blink && self.auto_blink)
+            .then(|| self.last_blink + Duration::from_secs_f32(self.blink_interval.max(0.05)))
+    }
+
+    /// Whether a horizontal position (in physical pixels) grabs the swipe divider
+    pub(crate) fn grabs_divider(&self, x: f64, window_width: u32) -> bool {
+        const GRAB_DISTANCE: f64 = 8.0;
+        self.mode == CompareMode::Swipe
+            && (x - (self.swipe as f64) * (window_width as f64)).abs() <= GRAB_DISTANCE
+    }
+
+    /// The compositing mode passed to `map_shader.wgsl`
+    pub(crate) fn shader_mode(&self) -> u32 {
+        match self.mode {
+            CompareMode::SideBySide => 1,
+            CompareMode::Swipe => 2,
+            CompareMode::Blink if self.blink_show_compare => 3,
+            CompareMode::Blink => 0,
+            CompareMode::Difference => 4,
+        }
+    }
+}
@@ -0,0 +1,371 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use log::*;
+use serde::{Deserialize, Serialize};
+use winit::event::KeyEvent;
+use winit::keyboard::{Key, ModifiersState};
+
+use crate::{default_workers, RenderBackend};
+
+/// User settings, read from `config.json` in the config directory
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub(crate) struct Config {
+    pub(crate) keybindings: KeyBindings,
+    /// Where screenshots are saved, the current directory if not set
+    pub(crate) screenshot_dir: Option<PathBuf>,
+    pub(crate) minimap: MinimapConfig,
+    /// Places to search for, a CSV or GeoJSON file
+    pub(crate) gazetteer: Option<PathBuf>,
+    pub(crate) render: RenderConfig,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub(crate) struct RenderConfig {
+    /// When rendering is slow, first render at 1/`preview_divisor` of the width and height. 1 turns this off.
+    pub(crate) preview_divisor: u32,
+    /// Compose the view from cached tiles, so panning only renders the newly exposed tiles.
+    /// Turned off, every view is rendered as a whole, like `--render` does.
+    pub(crate) tiles: bool,
+    /// Tile width and height in pixels
+    pub(crate) tile_size: u32,
+    /// Tiles are rendered in blocks of `metatile` by `metatile` tiles
+    pub(crate) metatile: u32,
+    /// Pixels rendered around each metatile, so labels near its edges are placed like in a larger render
+    pub(crate) tile_margin: u32,
+    /// The number of tiles kept per stylesheet
+    pub(crate) tile_cache_size: usize,
+    /// Threads rendering tiles in parallel, each with its own copy of the stylesheet. One per
+    /// core (up to 8) if not set.
+    pub(crate) workers: Option<usize>,
+    /// Pixels rendered beyond each edge of the window, so panning shows map instead of blank edges
+    pub(crate) overscan: u32,
+    /// The Mapnik renderer, `"agg"` to see what tile servers render
+    pub(crate) backend: RenderBackend,
+}
+
+impl RenderConfig {
+    /// The number of workers, counting the cores of this machine if it isn't set
+    pub(crate) fn worker_count(&self) -> usize {
+        self.workers.unwrap_or_else(default_workers)
+    }
+}
+
+impl Default for RenderConfig {
+    fn default() -> Self {
+        Self {
+            preview_divisor: 2,
+            tiles: true,
+            tile_size: 256,
+            metatile: 2,
+            tile_margin: 128,
+            tile_cache_size: 256,
+            workers: None,
+            overscan: 128,
+            backend: RenderBackend::default(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub(crate) struct MinimapConfig {
+    pub(crate) enabled: bool,
+    /// A simplified stylesheet for the minimap, the main stylesheet if not set
+    pub(crate) stylesheet: Option<PathBuf>,
+    /// Size in pixels
+    pub(crate) size: (u32, u32),
+    /// How many times more the minimap shows than the main view
+    pub(crate) zoom_out: f64,
+}
+
+impl Default for MinimapConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            stylesheet: None,
+            size: (200, 150),
+            zoom_out: 16.0,
+        }
+    }
+}
+
+impl Config {
+    /// Load the config, the defaults if the file doesn't exist.
+    ///
+    /// Nothing is written, so defaults that depend on the machine or change between versions
+    /// only apply as long as they aren't set.
+    pub(crate) fn load(path: impl AsRef<Path>) -> anyhow::Result<Config> {
+        let path = path.as_ref();
+        if !path.exists() {
+            info!("No config at {}, using the defaults", path.display());
+            return Ok(Config::default());
+        }
+
+        let config = serde_json::from_str(&fs::read_to_string(path)?)
+            .map_err(|err| anyhow::format_err!("Invalid config {}: {}", path.display(), err))?;
+        return Ok(config);
+    }
+}
+
+/// Something a key binding can do
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Action {
+    PanLeft,
+    PanRight,
+    PanUp,
+    PanDown,
+    ZoomIn,
+    ZoomOut,
+    Reload,
+    Screenshot,
+    ToggleControls,
+    Back,
+    Forward,
+    Search,
+}
+
+impl Action {
+    /// Whether holding the key down repeats the action
+    pub(crate) fn repeats(&self) -> bool {
+        matches!(self, Action::PanLeft | Action::PanRight | Action::PanUp | Action::PanDown | Action::ZoomIn | Action::ZoomOut)
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub(crate) struct KeyBindings {
+    pub(crate) pan_left: Vec<KeyBinding>,
+    pub(crate) pan_right: Vec<KeyBinding>,
+    pub(crate) pan_up: Vec<KeyBinding>,
+    pub(crate) pan_down: Vec<KeyBinding>,
+    pub(crate) zoom_in: Vec<KeyBinding>,
+    pub(crate) zoom_out: Vec<KeyBinding>,
+    pub(crate) reload: Vec<KeyBinding>,
+    pub(crate) screenshot: Vec<KeyBinding>,
+    pub(crate) toggle_controls: Vec<KeyBinding>,
+    pub(crate) back: Vec<KeyBinding>,
+    pub(crate) forward: Vec<KeyBinding>,
+    pub(crate) search: Vec<KeyBinding>,
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        fn keys(keys: &[&str]) -> Vec<KeyBinding> {
+            keys.iter().map(|key| key.parse().unwrap()).collect()
+        }
+
+        Self {
+            pan_left: keys(&["Left", "A"]),
+            pan_right: keys(&["Right", "D"]),
+            pan_up: keys(&["Up", "W"]),
+            pan_down: keys(&["Down", "S"]),
+            zoom_in: keys(&["Plus", "="]),
+            zoom_out: keys(&["Minus"]),
+            reload: keys(&["Ctrl+R"]),
+            screenshot: keys(&["Ctrl+S"]),
+            toggle_controls: keys(&["F1"]),
+            back: keys(&["Alt+Left"]),
+            forward: keys(&["Alt+Right"]),
+            search: keys(&["Ctrl+F"]),
+        }
+    }
+}
+
+impl KeyBindings {
+    /// The action bound to a key press
+    pub(crate) fn action(&self, event: &KeyEvent, modifiers: ModifiersState) -> Option<Action> {
+        let bindings = [
+            (Action::PanLeft, &self.pan_left),
+            (Action::PanRight, &self.pan_right),
+            (Action::PanUp, &self.pan_up),
+            (Action::PanDown, &self.pan_down),
+            (Action::ZoomIn, &self.zoom_in),
+            (Action::ZoomOut, &self.zoom_out),
+            (Action::Reload, &self.reload),
+            (Action::Screenshot, &self.screenshot),
+            (Action::ToggleControls, &self.toggle_controls),
+            (Action::Back, &self.back),
+            (Action::Forward, &self.forward),
+            (Action::Search, &self.search),
+        ];
+        bindings.into_iter()
+            .find(|(_, keys)| keys.iter().any(|key| key.matches(event, modifiers)))
+            .map(|(action, _)| action)
+    }
+}
+
+/// A key with modifiers, written like `Ctrl+Shift+R`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub(crate) struct KeyBinding {
+    ctrl: bool,
+    alt: bool,
+    shift: bool,
+    logo: bool,
+    /// Lowercase character, or the lowercase name of a named key (e.g. `arrowleft`, `f1`)
+    key: String,
+}
+
+/// Names that can be used in the config, and the key names used by winit
+const KEY_ALIASES: [(&str, &str); 7] = [
+    ("left", "arrowleft"),
+    ("right", "arrowright"),
+    ("up", "arrowup"),
+    ("down", "arrowdown"),
+    ("esc", "escape"),
+    ("plus", "+"),
+    ("minus", "-"),
+];
+
+fn key_name(key: &Key) -> Option<String> {
+    match key {
+        Key::Named(named) => Some(format!("{:?}", named).to_lowercase()),
+        Key::Character(c) => Some(c.to_lowercase()),
+        _ => None,
+    }
+}
+
+impl KeyBinding {
+    fn matches(&self, event: &KeyEvent, modifiers: ModifiersState) -> bool {
+        let Some(key) = key_name(&event.logical_key) else { return false };
+        // Shift changes which symbol is typed (`=` vs `+`), so it only counts for letters and named keys
+        let is_symbol = key.chars().count() == 1 && !key.chars().all(char::is_alphanumeric);
+        key == self.key
+            && modifiers.control_key() == self.ctrl
+            && modifiers.alt_key() == self.alt
+            && modifiers.super_key() == self.logo
+            && (is_symbol || modifiers.shift_key() == self.shift)
+    }
+}
+
+impl FromStr for KeyBinding {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts: Vec<&str> = s.split('+').collect();
+        // Also accept `+` itself, as in `Ctrl++`. `Ctrl+` has no key.
+        let key = if s == "+" || s.ends_with("++") {
+            parts.truncate(parts.len().saturating_sub(2));
+            "+".to_string()
+        } else {
+            parts.pop().unwrap().trim().to_lowercase()
+        };
+        if key.is_empty() {
+            return Err(anyhow::format_err!("No key in key binding `{}`", s));
+        }
+        let key = KEY_ALIASES.iter()
+            .find(|(alias, _)| *alias == key)
+            .map(|(_, name)| name.to_string())
+            .unwrap_or(key);
+
+        let mut binding = KeyBinding { ctrl: false, alt: false, shift: false, logo: false, key };
+        for modifier in parts {
+            match modifier.trim().to_lowercase().as_str() {
+                "ctrl" | "control" => binding.ctrl = true,
+                "alt" | "option" => binding.alt = true,
+                "shift" => binding.shift = true,
+                "super" | "cmd" | "logo" | "meta" => binding.logo = true,
+                _ => return Err(anyhow::format_err!("Unknown modifier `{}` in key binding `{}`", modifier, s)),
+            }
+        }
+        return Ok(binding);
+    }
+}
+
+impl TryFrom<String> for KeyBinding {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<KeyBinding> for String {
+    fn from(value: KeyBinding) -> Self {
+        value.to_string()
+    }
+}
+
+impl std::fmt::Display for KeyBinding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (enabled, name) in [(self.ctrl, "Ctrl"), (self.alt, "Alt"), (self.shift, "Shift"), (self.logo, "Super")] {
+            if enabled {
+                f.write_fmt(format_args!("{}+", name))?;
+            }
+        }
+        let key = KEY_ALIASES.iter()
+            .find(|(_, name)| *name == self.key)
+            .map(|(alias, _)| alias.to_string())
+            .unwrap_or(self.key.clone());
+        let mut chars = key.chars();
+        match chars.next() {
+            Some(first) => f.write_fmt(format_args!("{}{}", first.to_uppercase(), chars.as_str())),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn binding(s: &str) -> KeyBinding {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn default_bindings_round_trip() {
+        let bindings = KeyBindings::default();
+        for keys in [
+            &bindings.pan_left, &bindings.pan_right, &bindings.pan_up, &bindings.pan_down,
+            &bindings.zoom_in, &bindings.zoom_out, &bindings.reload, &bindings.screenshot,
+            &bindings.toggle_controls, &bindings.back, &bindings.forward, &bindings.search,
+        ] {
+            assert!(!keys.is_empty());
+            for key in keys {
+                assert_eq!(&binding(&key.to_string()), key, "{key}");
+            }
+        }
+    }
+
+    #[test]
+    fn parses_modifiers_in_any_order_and_case() {
+        let expected = KeyBinding { ctrl: true, alt: false, shift: true, logo: false, key: "r".to_string() };
+        assert_eq!(binding("Ctrl+Shift+R"), expected);
+        assert_eq!(binding("shift+ctrl+r"), expected);
+        assert_eq!(binding(" Control + SHIFT + r "), expected);
+        assert_eq!(binding("Shift+Control+R").to_string(), "Ctrl+Shift+R");
+        assert_eq!(binding("cmd+option+F5").to_string(), "Alt+Super+F5");
+    }
+
+    #[test]
+    fn parses_aliases_and_plus() {
+        assert_eq!(binding("Left").key, "arrowleft");
+        assert_eq!(binding("Alt+left").to_string(), "Alt+Left");
+        assert_eq!(binding("+"), binding("Plus"));
+        assert_eq!(binding("Ctrl++"), binding("Ctrl+Plus"));
+        assert_eq!(binding("Ctrl++").to_string(), "Ctrl+Plus");
+        assert_eq!(binding("=").to_string(), "=");
+        assert_eq!(binding("PageUp").to_string(), "Pageup");
+        assert_eq!(binding("Pageup"), binding("PAGEUP"));
+    }
+
+    #[test]
+    fn rejects_invalid_bindings() {
+        for s in ["", " ", "Ctrl+", "Hyper+A", "Ctrl++Shift"] {
+            assert!(s.parse::<KeyBinding>().is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn reads_bindings_from_json() {
+        let bindings: KeyBindings = serde_json::from_str(r#"{ "reload": ["F5", "Ctrl+R"] }"#).unwrap();
+        assert_eq!(bindings.reload, vec![binding("F5"), binding("Ctrl+R")]);
+        assert_eq!(bindings.search, KeyBindings::default().search);
+        assert!(serde_json::from_str::<KeyBindings>(r#"{ "reload": ["Ctrl+"] }"#).is_err());
+    }
+}
@@ -2,7 +2,7 @@ use cxx::memory::SharedPtrTarget;
 use cxx::SharedPtr;
 use serde::{Deserialize, Serialize};
 
-use crate::{Box2d, Point, Projection, ProjectionExt};
+use crate::{Box2d, Point, ProjTransform, ProjTransformExt, ProjTransformMemberExt, Projection, ProjectionExt, ProjectionMemberExt};
 
 fn shared_ptr_null<T: SharedPtrTarget>() -> SharedPtr<T> {
     return SharedPtr::null();
@@ -10,10 +10,9 @@ fn shared_ptr_null<T: SharedPtrTarget>() -> SharedPtr<T> {
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Controls {
-    // would prefer f64 for these
-    pub center_x: f32,
-    pub center_y: f32,
-    pub units_per_pixel_scale: f32,
+    pub center_x: f64,
+    pub center_y: f64,
+    pub units_per_pixel_scale: f64,
     pub map_width: u32,
     pub map_height: u32,
     // #[serde(skip)]
@@ -26,6 +25,12 @@ pub struct Controls {
     #[serde(skip, default = "shared_ptr_null")]
     // #[serde(deserialize_with = "projection_de", serialize_with = "projection_se")]
     output_projection: SharedPtr<Projection>,
+    #[serde(skip, default = "shared_ptr_null")]
+    proj_transform: SharedPtr<ProjTransform>,
+    #[serde(skip, default = "shared_ptr_null")]
+    geographic_projection: SharedPtr<Projection>,
+    #[serde(skip, default = "shared_ptr_null")]
+    geographic_transform: SharedPtr<ProjTransform>,
 
     #[serde(skip)]
     cache_input_projection_srs: String,
@@ -37,6 +42,14 @@ unsafe impl Send for Controls {}
 unsafe impl Sync for Controls {}
 
 impl Controls {
+    pub fn input_projection_srs(&self) -> &str {
+        &self.input_projection_srs
+    }
+
+    pub fn output_projection_srs(&self) -> &str {
+        &self.output_projection_srs
+    }
+
     pub fn input_projection(&self) -> SharedPtr<Projection> {
         self.input_projection.clone()
     }
@@ -45,12 +58,29 @@ impl Controls {
         self.output_projection.clone()
     }
 
+    /// Transforms from the input to the output projection
+    pub fn proj_transform(&self) -> SharedPtr<ProjTransform> {
+        self.proj_transform.clone()
+    }
+
+    fn update_proj_transform(&mut self) -> anyhow::Result<()> {
+        if self.input_projection.is_null() || self.output_projection.is_null() {
+            return Ok(());
+        }
+        self.proj_transform = ProjTransform::new(&self.input_projection, &self.output_projection)?;
+        if self.geographic_projection.is_null() {
+            self.geographic_projection = Projection::new("epsg:4326")?;
+        }
+        self.geographic_transform = ProjTransform::new(&self.output_projection, &self.geographic_projection)?;
+        Ok(())
+    }
+
     pub fn set_input_projection(&mut self, projection: impl Into<String>) -> anyhow::Result<()> {
         let srs: String = projection.into();
         self.input_projection = Projection::new(&srs)?;
         self.input_projection_srs = srs.clone();
         self.input_projection_srs = srs;
-        Ok(())
+        self.update_proj_transform()
     }
 
     pub fn set_output_projection(&mut self, projection: impl Into<String>) -> anyhow::Result<()> {
@@ -58,7 +88,7 @@ impl Controls {
         self.output_projection = Projection::new(&srs)?;
         self.output_projection_srs = srs.clone();
         self.cache_output_projection_srs = srs;
-        Ok(())
+        self.update_proj_transform()
     }
 
     pub fn updating_input_projection<R>(&mut self, cb: impl Fn(&mut String) -> R) -> anyhow::Result<R> {
@@ -66,6 +96,7 @@ impl Controls {
         if self.input_projection_srs != self.cache_input_projection_srs {
             self.input_projection = Projection::new(&self.input_projection_srs)?;
             self.cache_input_projection_srs = self.input_projection_srs.clone();
+            self.update_proj_transform()?;
         }
         Ok(ret)
     }
@@ -75,21 +106,124 @@ impl Controls {
         if self.output_projection_srs != self.cache_output_projection_srs {
             self.output_projection = Projection::new(&self.output_projection_srs)?;
             self.cache_output_projection_srs = self.output_projection_srs.clone();
+            self.update_proj_transform()?;
         }
         Ok(ret)
     }
 
     pub fn create_center_box(&self, w: u32, h: u32) -> Box2d<f64> {
-        let center = Point::<f64>::new(self.center_x.into(), self.center_y.into());
+        let center = Point::<f64>::new(self.center_x, self.center_y);
         return Box2d::<f64>::new_centered(
             &center,
             self.input_projection.clone(),
             self.output_projection.clone(),
-            self.units_per_pixel_scale.into(),
+            self.units_per_pixel_scale,
             w, h
         );
     }
 
+    /// Convert a point in the output projection to the input projection
+    pub fn unproject(&self, projected: &Point<f64>) -> anyhow::Result<Point<f64>> {
+        return Ok(self.proj_transform.backward(projected)?);
+    }
+
+    /// Convert a point in the output projection to longitude and latitude
+    pub fn to_lonlat(&self, projected: &Point<f64>) -> anyhow::Result<Point<f64>> {
+        return Ok(self.geographic_transform.forward(projected)?);
+    }
+
+    /// Convert a point in any projection to the output projection
+    pub fn project_from(&self, srs: &str, point: &Point<f64>) -> anyhow::Result<Point<f64>> {
+        let projection = Projection::new(srs)?;
+        let transform = ProjTransform::new(&projection, &self.output_projection)?;
+        return Ok(transform.forward(point)?);
+    }
+
+    /// The scale denominator of the map, like Mapnik computes it
+    pub fn scale_denominator(&self) -> f64 {
+        return self.units_per_pixel_scale / self.units_per_scale_denominator();
+    }
+
+    pub fn set_scale_denominator(&mut self, denominator: f64) {
+        self.units_per_pixel_scale = denominator * self.units_per_scale_denominator();
+    }
+
+    /// Units per pixel at a scale of 1:1
+    fn units_per_scale_denominator(&self) -> f64 {
+        // Mapnik assumes pixels of 0.28 mm
+        let mut units = 0.00028;
+        if self.output_projection.is_geographic() {
+            units /= 6378137.0 * 2.0 * std::f64::consts::PI / 360.0;
+        }
+        return units;
+    }
+
+    /// The center of the view in the output projection
+    pub fn projected_center(&self) -> anyhow::Result<Point<f64>> {
+        let center = Point::<f64>::new(self.center_x, self.center_y);
+        return Ok(self.proj_transform.forward(&center)?);
+    }
+
+    /// The point in the output projection shown at `pixel`.
+    ///
+    /// `pixel` is in map pixels, relative to the top left corner of the map.
+    pub fn projected_at(&self, pixel: [f64; 2]) -> anyhow::Result<Point<f64>> {
+        let center = self.projected_center()?;
+        let [dx, dy] = self.offset_from_center(pixel);
+        return Ok(Point::new(center.x + dx * self.units_per_pixel_scale, center.y + dy * self.units_per_pixel_scale));
+    }
+
+    /// Move the view so that `projected` (in the output projection) is shown at `pixel`
+    pub fn move_to(&mut self, projected: Point<f64>, pixel: [f64; 2]) -> anyhow::Result<()> {
+        let [dx, dy] = self.offset_from_center(pixel);
+        let center = Point::new(projected.x - dx * self.units_per_pixel_scale, projected.y - dy * self.units_per_pixel_scale);
+        let center = self.proj_transform.backward(&center)?;
+        self.center_x = center.x;
+        self.center_y = center.y;
+        Ok(())
+    }
+
+    /// Move the view by a number of map pixels, positive values move it right and down
+    pub fn pan(&mut self, dx: f64, dy: f64) -> anyhow::Result<()> {
+        let center = [self.map_width as f64 / 2.0, self.map_height as f64 / 2.0];
+        let projected = self.projected_at([center[0] + dx, center[1] + dy])?;
+        self.move_to(projected, center)
+    }
+
+    /// Zoom by `factor` (below 1 zooms in) while the map point at `anchor` (in map pixels) stays where it is
+    pub fn zoom_at(&mut self, factor: f64, anchor: [f64; 2]) -> anyhow::Result<()> {
+        let projected = self.projected_at(anchor)?;
+        self.units_per_pixel_scale *= factor;
+        self.move_to(projected, anchor)
+    }
+
+    /// Fit the rectangle between two map pixels into the view.
+    ///
+    /// When zooming out, the current view shrinks into the rectangle instead.
+    pub fn fit_box(&mut self, corner: [f64; 2], opposite_corner: [f64; 2], zoom_out: bool) -> anyhow::Result<()> {
+        let box_w = (corner[0] - opposite_corner[0]).abs().max(1.0);
+        let box_h = (corner[1] - opposite_corner[1]).abs().max(1.0);
+        let box_center = [(corner[0] + opposite_corner[0]) / 2.0, (corner[1] + opposite_corner[1]) / 2.0];
+        let view_center = [self.map_width as f64 / 2.0, self.map_height as f64 / 2.0];
+        let factor = (box_w / self.map_width as f64).max(box_h / self.map_height as f64);
+
+        if zoom_out {
+            let projected = self.projected_center()?;
+            self.units_per_pixel_scale /= factor;
+            // The current center ends up in the middle of the rectangle
+            self.move_to(projected, box_center)
+        } else {
+            let projected = self.projected_at(box_center)?;
+            self.units_per_pixel_scale *= factor;
+            self.move_to(projected, view_center)
+        }
+    }
+
+    /// Offset of a map pixel from the center, with y pointing up like the output projection
+    fn offset_from_center(&self, pixel: [f64; 2]) -> [f64; 2] {
+        [pixel[0] - self.map_width as f64 / 2.0, self.map_height as f64 / 2.0 - pixel[1]]
+    }
+
     pub fn from_json(json: serde_json::Value) -> anyhow::Result<Self> {
         let mut controls: Self = serde_json::from_value(json)?;
         controls.set_input_projection(controls.input_projection_srs.clone())?;
@@ -103,7 +237,7 @@ impl Default for Controls {
         let isrs = "epsg:3812".to_string();
         let osrs = "+proj=merc +lat_ts=0 +lon_0=0 +x_0=0 +y_0=0 +R=6371000 +units=m +no_defs +type=crs".to_string();
 
-        Self {
+        let mut controls = Self {
             center_x: 549000.00,
             center_y: 713900.00,
             units_per_pixel_scale: 10.0,
@@ -113,8 +247,13 @@ impl Default for Controls {
             output_projection_srs: osrs.clone(),
             input_projection: Projection::new(&isrs).unwrap(),
             output_projection: Projection::new(&osrs).unwrap(),
+            proj_transform: SharedPtr::null(),
+            geographic_projection: SharedPtr::null(),
+            geographic_transform: SharedPtr::null(),
             cache_input_projection_srs: isrs,
             cache_output_projection_srs: osrs,
-        }
+        };
+        controls.update_proj_transform().unwrap();
+        return controls;
     }
 }
@@ -0,0 +1,220 @@
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use super::controls::Controls;
+
+/// A view that can be returned to
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub(crate) struct View {
+    center_x: f64,
+    center_y: f64,
+    units_per_pixel_scale: f64,
+    /// The center is in this projection
+    input_projection_srs: String,
+}
+
+impl View {
+    pub(crate) fn of(controls: &Controls) -> Self {
+        Self {
+            center_x: controls.center_x,
+            center_y: controls.center_y,
+            units_per_pixel_scale: controls.units_per_pixel_scale,
+            input_projection_srs: controls.input_projection_srs().to_string(),
+        }
+    }
+
+    pub(crate) fn apply(&self, controls: &mut Controls) -> anyhow::Result<()> {
+        if controls.input_projection_srs() != self.input_projection_srs {
+            controls.set_input_projection(self.input_projection_srs.clone())?;
+        }
+        controls.center_x = self.center_x;
+        controls.center_y = self.center_y;
+        controls.units_per_pixel_scale = self.units_per_pixel_scale;
+        Ok(())
+    }
+}
+
+/// Back and forward navigation through the committed views
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct History {
+    entries: Vec<View>,
+    current: usize,
+    /// When the last mergeable change was committed
+    #[serde(skip)]
+    last_merge: Option<Instant>,
+}
+
+impl History {
+    const MAX_ENTRIES: usize = 200;
+    /// Continuous changes (scrolling, typing, holding a key) closer together than this form a single entry
+    const MERGE_WINDOW: Duration = Duration::from_secs(1);
+
+    pub(crate) fn new(view: View) -> Self {
+        Self {
+            entries: vec![view],
+            current: 0,
+            last_merge: None,
+        }
+    }
+
+    /// Restore the history of the last session, continuing from `view`
+    pub(crate) fn from_json(json: serde_json::Value, view: View) -> anyhow::Result<Self> {
+        let mut history: Self = serde_json::from_value(json)?;
+        if history.entries.is_empty() {
+            return Ok(Self::new(view));
+        }
+        history.current = history.current.min(history.entries.len() - 1);
+        history.commit(view, false);
+        return Ok(history);
+    }
+
+    /// Add a view after the current one, dropping the forward history.
+    ///
+    /// With `merge`, the view replaces the current entry if that was also committed with `merge`
+    /// just before.
+    pub(crate) fn commit(&mut self, view: View, merge: bool) {
+        let now = Instant::now();
+        let merging = merge && self.last_merge.is_some_and(|last| now - last < Self::MERGE_WINDOW);
+        self.last_merge = if merge { Some(now) } else { None };
+
+        if self.entries[self.current] == view {
+            return;
+        }
+        self.entries.truncate(self.current + 1);
+        if merging && self.current > 0 {
+            self.entries[self.current] = view;
+            return;
+        }
+        self.entries.push(view);
+        if self.entries.len() > Self::MAX_ENTRIES {
+            self.entries.remove(0);
+        }
+        self.current = self.entries.len() - 1;
+    }
+
+    pub(crate) fn back(&mut self) -> Option<&View> {
+        if self.current == 0 {
+            return None;
+        }
+        self.current -= 1;
+        self.last_merge = None;
+        return Some(&self.entries[self.current]);
+    }
+
+    pub(crate) fn forward(&mut self) -> Option<&View> {
+        if self.current + 1 >= self.entries.len() {
+            return None;
+        }
+        self.current += 1;
+        self.last_merge = None;
+        return Some(&self.entries[self.current]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn view(x: i32) -> View {
+        View {
+            center_x: x as f64,
+            center_y: 0.0,
+            units_per_pixel_scale: 1.0,
+            input_projection_srs: "epsg:4326".to_string(),
+        }
+    }
+
+    #[test]
+    fn goes_back_and_forward() {
+        let mut history = History::new(view(0));
+        history.commit(view(1), false);
+        history.commit(view(2), false);
+        assert_eq!(history.back(), Some(&view(1)));
+        assert_eq!(history.back(), Some(&view(0)));
+        assert_eq!(history.back(), None);
+        assert_eq!(history.forward(), Some(&view(1)));
+        assert_eq!(history.forward(), Some(&view(2)));
+        assert_eq!(history.forward(), None);
+    }
+
+    #[test]
+    fn ignores_the_current_view() {
+        let mut history = History::new(view(0));
+        history.commit(view(0), false);
+        history.commit(view(1), false);
+        history.commit(view(1), true);
+        assert_eq!(history.entries, vec![view(0), view(1)]);
+    }
+
+    #[test]
+    fn drops_the_forward_history() {
+        let mut history = History::new(view(0));
+        history.commit(view(1), false);
+        history.commit(view(2), false);
+        history.back();
+        history.back();
+        history.commit(view(3), false);
+        assert_eq!(history.entries, vec![view(0), view(3)]);
+        assert_eq!(history.forward(), None);
+    }
+
+    #[test]
+    fn merges_continuous_changes() {
+        let mut history = History::new(view(0));
+        // The first change of a series is an entry of its own, the initial view is kept
+        history.commit(view(1), true);
+        history.commit(view(2), true);
+        history.commit(view(3), true);
+        assert_eq!(history.entries, vec![view(0), view(3)]);
+
+        // A change that can't be merged ends the series
+        history.commit(view(4), false);
+        history.commit(view(5), true);
+        assert_eq!(history.entries, vec![view(0), view(3), view(4), view(5)]);
+
+        // So does navigating
+        history.back();
+        history.commit(view(6), true);
+        assert_eq!(history.entries, vec![view(0), view(3), view(4), view(6)]);
+    }
+
+    #[test]
+    fn keeps_the_most_recent_entries() {
+        let mut history = History::new(view(0));
+        for x in 1..=250 {
+            history.commit(view(x), false);
+        }
+        assert_eq!(history.entries.len(), History::MAX_ENTRIES);
+        assert_eq!(history.entries.first(), Some(&view(51)));
+        assert_eq!(history.current, History::MAX_ENTRIES - 1);
+        let mut steps = 0;
+        while history.back().is_some() {
+            steps += 1;
+        }
+        assert_eq!(steps, History::MAX_ENTRIES - 1);
+    }
+
+    #[test]
+    fn restores_a_stale_history() {
+        let mut history = History::new(view(0));
+        history.commit(view(1), false);
+        history.current = 5;
+        let json = serde_json::to_value(&history).unwrap();
+
+        let mut restored = History::from_json(json.clone(), view(2)).unwrap();
+        assert_eq!(restored.entries, vec![view(0), view(1), view(2)]);
+        assert_eq!(restored.back(), Some(&view(1)));
+
+        // Continuing from the view that was current doesn't add an entry
+        let restored = History::from_json(json, view(1)).unwrap();
+        assert_eq!(restored.entries, vec![view(0), view(1)]);
+        assert_eq!(restored.current, 1);
+
+        let restored = History::from_json(serde_json::json!({ "entries": [], "current": 3 }), view(7)).unwrap();
+        assert_eq!(restored.entries, vec![view(7)]);
+        assert_eq!(restored.current, 0);
+
+        assert!(History::from_json(serde_json::json!({ "entries": 1 }), view(0)).is_err());
+    }
+}
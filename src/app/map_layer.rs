@@ -0,0 +1,528 @@
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use cxx::SharedPtr;
+use log::*;
+use winit::event_loop::EventLoopProxy;
+
+use crate::ext::ResultExt as _;
+use crate::{only_styles_changed, ProjTransformMemberExt as _, styles_only, Box2d, FrameQuality, MapRendererMemberExt as _, Point, Projection, ProjTransform, RenderBackend, RenderPool, ScreenMapRenderer, ScreenMapRendererBuffer, ScreenMapRendererBuffers, ScreenMapRendererJoinHandle, Stylesheet, TiledView, Tiling, UserDataSender};
+use super::animation::DisplayedView;
+use super::AppEvent;
+use super::config::RenderConfig;
+use super::controls::Controls;
+
+/// What the map renderers of a window share
+pub(crate) struct RenderContext<'a> {
+    pub(crate) device: &'a wgpu::Device,
+    pub(crate) basepath: &'a Path,
+    /// Wakes up the UI when a frame is rendered
+    pub(crate) proxy: &'a EventLoopProxy<AppEvent>,
+}
+
+pub(crate) struct UserDataStatic {
+    /// Size of the viewport
+    w: u32, h: u32,
+    /// Pixels rendered beyond each edge of the viewport, shown while panning until the next frame arrives
+    overscan: u32,
+    input_projection: SharedPtr<Projection>,
+    output_projection: SharedPtr<Projection>,
+    output_projection_srs: String,
+    /// From the input to the output projection
+    proj_transform: SharedPtr<ProjTransform>,
+    units_per_pixel_scale: f64,
+}
+
+impl UserDataStatic {
+    pub(crate) fn new(controls: &Controls, overscan: u32) -> Self {
+        Self {
+            overscan,
+            ..Self::with_size(controls, controls.map_width, controls.map_height, controls.units_per_pixel_scale)
+        }
+    }
+
+    /// The projections of `controls`, with another size and scale
+    pub(crate) fn with_size(controls: &Controls, w: u32, h: u32, units_per_pixel_scale: f64) -> Self {
+        Self {
+            w, h,
+            overscan: 0,
+            input_projection: controls.input_projection(),
+            output_projection: controls.output_projection(),
+            output_projection_srs: controls.output_projection_srs().to_string(),
+            proj_transform: controls.proj_transform(),
+            units_per_pixel_scale,
+        }
+    }
+
+    pub(crate) fn units_per_pixel_scale(&self) -> f64 {
+        self.units_per_pixel_scale
+    }
+
+    /// Size of the rendered frames: the viewport and the overscan margin around it
+    pub(crate) fn rendered_size(&self) -> (u32, u32) {
+        (self.w + 2 * self.overscan, self.h + 2 * self.overscan)
+    }
+
+    /// Width and height of the viewport
+    pub(crate) fn viewport_size(&self) -> (u32, u32) {
+        (self.w, self.h)
+    }
+
+    /// The pixels of the viewport in a rendered frame, without the overscan margin
+    pub(crate) fn viewport_pixels(&self, buffer: &[u8]) -> Vec<u8> {
+        let (w, _) = self.rendered_size();
+        let (row, margin) = (w as usize * 4, self.overscan as usize * 4);
+        buffer.chunks_exact(row)
+            .skip(self.overscan as usize)
+            .take(self.h as usize)
+            .flat_map(|pixels| &pixels[margin..margin + self.w as usize * 4])
+            .copied()
+            .collect()
+    }
+
+    /// The box rendered around a center in the input projection, including the overscan margin
+    fn center_box(&self, center_x: f64, center_y: f64) -> Box2d<f64> {
+        let (w, h) = self.rendered_size();
+        Box2d::<f64>::new_centered(
+            &Point::<f64>::new(center_x, center_y),
+            self.input_projection.clone(),
+            self.output_projection.clone(),
+            self.units_per_pixel_scale,
+            w,
+            h
+        )
+    }
+}
+
+unsafe impl Send for UserDataStatic {}
+unsafe impl Sync for UserDataStatic {}
+
+/// The center of the view and the parameters that rarely change
+pub(crate) type MapUserData = (f64, f64, Arc<UserDataStatic>);
+
+/// Compose the frames of `MapUserData` views from tiles
+fn map_tiling(config: &RenderConfig) -> Tiling<MapUserData> {
+    Tiling::new(
+        config.tile_size,
+        config.metatile,
+        config.tile_margin,
+        config.tile_cache_size,
+        Box::new(|ud: &MapUserData| {
+            Ok(TiledView {
+                center: ud.2.proj_transform.forward(&Point::new(ud.0, ud.1))?,
+                units_per_pixel: ud.2.units_per_pixel_scale,
+                srs: ud.2.output_projection_srs.clone(),
+            })
+        }),
+        Box::new(|ud: &MapUserData, center| {
+            let center = ud.2.proj_transform.backward(&center)?;
+            Ok((center.x, center.y, ud.2.clone()))
+        }),
+    )
+}
+
+/// Format of the textures the frames are uploaded to.
+///
+/// The frames are sRGB encoded, premultiplied BGRA, sampling decodes them to linear and the surface encodes
+/// them again, so opaque pixels end up on screen with the bytes cairo rendered.
+pub(crate) const FRAME_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Bgra8UnormSrgb;
+
+pub(crate) fn create_map_texture(
+    device: &wgpu::Device,
+    map_bind_group_layout: &wgpu::BindGroupLayout,
+    w: u32, h: u32,
+) -> (
+    wgpu::Texture,
+    wgpu::TextureView,
+    wgpu::Texture,
+    wgpu::TextureView,
+    wgpu::Sampler,
+    wgpu::BindGroup,
+) {
+    let create_texture = |label| device.create_texture(&wgpu::TextureDescriptor {
+        size: wgpu::Extent3d {
+            width: w,
+            height: h,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: FRAME_FORMAT,
+        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST | wgpu::TextureUsages::COPY_SRC,
+        label: Some(label),
+        view_formats: &[]
+    });
+    let map_texture = create_texture("MapTexture");
+    let map_view = map_texture.create_view(&wgpu::TextureViewDescriptor::default());
+    // The frame shown before the current one, to cross-fade from
+    let previous_texture = create_texture("PreviousMapTexture");
+    let previous_view = previous_texture.create_view(&wgpu::TextureViewDescriptor::default());
+    let map_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+        address_mode_u: wgpu::AddressMode::ClampToEdge,
+        address_mode_v: wgpu::AddressMode::ClampToEdge,
+        address_mode_w: wgpu::AddressMode::ClampToEdge,
+        mag_filter: wgpu::FilterMode::Linear,
+        min_filter: wgpu::FilterMode::Linear,
+        mipmap_filter: wgpu::FilterMode::Nearest,
+        ..Default::default()
+    });
+
+    let map_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("Map Bind Group"),
+        layout: &map_bind_group_layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(&map_view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::Sampler(&map_sampler),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: wgpu::BindingResource::TextureView(&previous_view),
+            }
+        ]
+    });
+
+    return (map_texture, map_view, previous_texture, previous_view, map_sampler, map_bind_group);
+}
+
+/// Start rendering `map_def` around `center`, at the size and scale of `static_user_data`
+pub(crate) fn create_map_renderer(
+    center: (f64, f64),
+    map_def: &Stylesheet,
+    base_path: impl AsRef<Path>,
+    static_user_data: Arc<UserDataStatic>,
+    render_config: Option<&RenderConfig>,
+    proxy: &EventLoopProxy<AppEvent>,
+) -> anyhow::Result<(
+    ScreenMapRenderer<MapUserData>,
+    // ScreenMapRendererJoinHandle,
+    // SyncSender<(f64, f64)>,
+    ScreenMapRendererBuffers<MapUserData>
+)> {
+    let (w, h) = static_user_data.rendered_size();
+    let bbox = static_user_data.center_box(center.0, center.1);
+    let base_path = base_path.as_ref();
+    let (mut map_renderer, buffers) = ScreenMapRenderer::new(
+        w, h,
+        map_def, base_path,
+        (center.0, center.1, static_user_data),
+        Box::new(|map_renderer, ud| {
+            let bbox = ud.2.center_box(ud.0, ud.1);
+            map_renderer.pin_mut()
+                .zoom_to_box(&bbox);
+        })
+    )?;
+    let proxy = proxy.clone();
+    map_renderer.set_on_frame(Some(Box::new(move || {
+        // Fails only when the event loop is gone
+        _ = proxy.send_event(AppEvent::NewFrame);
+    })));
+    if let Some(config) = render_config {
+        map_renderer.set_preview_divisor(Some(config.preview_divisor));
+        map_renderer.set_tiling(config.tiles.then(|| map_tiling(config)));
+        // Only tiles are spread over the workers, a view rendered as a whole can't be split up
+        if config.tiles && config.worker_count() > 1 {
+            map_renderer.set_render_pool(Some(RenderPool::new(config.worker_count(), map_def, base_path, config.backend)?));
+        }
+    }
+    #[allow(deprecated)] // TODO
+    let map_renderer_and_ud = map_renderer.map_renderer_and_user_data();
+    {
+        let mut guard = map_renderer_and_ud.lock().anyhow()?;
+        let renderer = guard.map_renderer_mut();
+        renderer.pin_mut().zoom_to_box(&bbox);
+        renderer.pin_mut().set_backend(render_config.map_or(RenderBackend::default(), |config| config.backend));
+    }
+
+    Ok((
+        map_renderer,
+        buffers
+    ))
+}
+
+/// Where a frame rendered for `ud` has to be drawn to match `view`
+fn placement(ud: &MapUserData, controls: &Controls, view: &DisplayedView) -> Option<([f32; 2], f32)> {
+    let transform = controls.proj_transform();
+    let buffer_center = transform.forward(&Point::new(ud.0, ud.1)).ok()?;
+
+    let delta = [
+        ((buffer_center.x - view.center.x) / (view.units_per_pixel * controls.map_width as f64 / 2.0)) as f32,
+        ((buffer_center.y - view.center.y) / (view.units_per_pixel * controls.map_height as f64 / 2.0)) as f32,
+    ];
+    let scale = (ud.2.units_per_pixel_scale / view.units_per_pixel) as f32;
+    Some((delta, scale))
+}
+
+/// A stylesheet rendered on its own renderer thread into a texture
+pub(crate) struct MapLayer {
+    pub(crate) map_def: Stylesheet,
+    pub(crate) texture: wgpu::Texture,
+    pub(crate) view: wgpu::TextureView,
+    pub(crate) previous_texture: wgpu::Texture,
+    pub(crate) previous_view: wgpu::TextureView,
+    pub(crate) sampler: wgpu::Sampler,
+    pub(crate) bind_group: wgpu::BindGroup,
+    /// The view of the frame in `previous_texture`
+    previous_user_data: Option<MapUserData>,
+    /// When the current buffer arrived
+    received_at: Instant,
+    map_renderer_join: Option<ScreenMapRendererJoinHandle<MapUserData>>,
+    ud_sender: UserDataSender<MapUserData>,
+    buffers: ScreenMapRendererBuffers<MapUserData>,
+    pub(crate) curr_buffer: Option<ScreenMapRendererBuffer<MapUserData>>,
+    /// The stylesheet as it was loaded by the renderer thread
+    loaded_xml: Option<String>,
+    /// Generation of the buffers rendered with the latest styles
+    generation: usize,
+    /// How the renderer thread renders, the defaults if not set
+    render_config: Option<RenderConfig>,
+    /// Wakes up the UI when a frame is rendered
+    proxy: EventLoopProxy<AppEvent>,
+}
+
+impl MapLayer {
+    pub(crate) fn new(
+        context: &RenderContext,
+        map_bind_group_layout: &wgpu::BindGroupLayout,
+        controls: &Controls,
+        map_def: Stylesheet,
+        static_user_data: Arc<UserDataStatic>,
+        render_config: Option<RenderConfig>,
+    ) -> anyhow::Result<Self> {
+        let loaded_xml = map_def.read_xml().ok();
+        let (
+            map_renderer,
+            buffers
+        ) = create_map_renderer((controls.center_x, controls.center_y), &map_def, context.basepath, static_user_data.clone(), render_config.as_ref(), context.proxy)?;
+        let (join, ud_sender) = map_renderer.start();
+        let (w, h) = static_user_data.rendered_size();
+
+        let (
+            texture,
+            view,
+            previous_texture,
+            previous_view,
+            sampler,
+            bind_group
+        ) = create_map_texture(context.device, map_bind_group_layout, w, h);
+
+        Ok(Self {
+            map_def,
+            texture,
+            view,
+            previous_texture,
+            previous_view,
+            sampler,
+            bind_group,
+            previous_user_data: None,
+            received_at: Instant::now(),
+            map_renderer_join: Some(join),
+            ud_sender,
+            buffers,
+            curr_buffer: None,
+            loaded_xml,
+            generation: 0,
+            render_config,
+            proxy: context.proxy.clone(),
+        })
+    }
+
+    pub(crate) fn send_user_data(&self, controls: &Controls, static_user_data: &Arc<UserDataStatic>) -> anyhow::Result<()> {
+        self.ud_sender.send((controls.center_x, controls.center_y, static_user_data.clone())).map_err(|err| anyhow::format_err!("{}", err))
+    }
+
+    /// Renders aborted for a newer view since the renderer was started
+    pub(crate) fn cancelled_renders(&self) -> usize {
+        self.map_renderer_join.as_ref().map_or(0, |join| join.cancelled_renders())
+    }
+
+    /// Render frames at the size of `static_user_data` from now on.
+    ///
+    /// The textures follow when the first frame of the new size arrives, until then the last
+    /// frame is stretched over the view.
+    pub(crate) fn resize(&self, controls: &Controls, static_user_data: &Arc<UserDataStatic>) -> anyhow::Result<()> {
+        let (w, h) = static_user_data.rendered_size();
+        self.ud_sender.resize(w, h, (controls.center_x, controls.center_y, static_user_data.clone()))
+    }
+
+    /// Upload the most recently rendered buffer to the texture, returns whether a new buffer arrived.
+    ///
+    /// The frame that was shown until now is kept, to cross-fade to the new one. When the size
+    /// of the frames changed, the textures are recreated and there is no fade.
+    pub(crate) fn update_buffer(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        map_bind_group_layout: &wgpu::BindGroupLayout,
+    ) -> anyhow::Result<bool> {
+        let Some(buffer) = self.buffers.get_buffer() else { return Ok(false) };
+        let (w, h) = buffer.size();
+        if (self.texture.width(), self.texture.height()) != (w, h) {
+            self.resize_texture(device, map_bind_group_layout, w, h);
+        } else if let Some(curr_buffer) = &self.curr_buffer {
+            let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: Some("Keep Previous Map Frame") });
+            encoder.copy_texture_to_texture(
+                self.texture.as_image_copy(),
+                self.previous_texture.as_image_copy(),
+                wgpu::Extent3d {
+                    width: w,
+                    height: h,
+                    depth_or_array_layers: 1
+                }
+            );
+            // Submitted before the write below, which only runs on the next submit
+            queue.submit(Some(encoder.finish()));
+            self.previous_user_data = Some(curr_buffer.user_data().clone());
+        }
+        queue.write_texture(
+            wgpu::TexelCopyTextureInfo {
+                texture: &self.texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+                aspect: wgpu::TextureAspect::All,
+            },
+            buffer.buffer(),
+            wgpu::TexelCopyBufferLayout {
+                offset: 0,
+                // The renderer thread removed cairo's row padding
+                bytes_per_row: Some(w * 4),
+                rows_per_image: Some(h)
+            },
+            wgpu::Extent3d {
+                width: w,
+                height: h,
+                depth_or_array_layers: 1
+            }
+        );
+        self.curr_buffer = Some(buffer);
+        self.received_at = Instant::now();
+        return Ok(true);
+    }
+
+    /// The part of the texture the view covers, per axis, the rest is the overscan margin.
+    ///
+    /// Relative to the size of the current frame, which differs from the view while resizing.
+    pub(crate) fn extent(&self, controls: &Controls) -> Option<[f32; 2]> {
+        let (w, h) = self.curr_buffer.as_ref()?.size();
+        Some([controls.map_width as f32 / w as f32, controls.map_height as f32 / h as f32])
+    }
+
+    /// Where the displayed texture has to be drawn to match the view on screen: the position of
+    /// its center in clip space and how much it has to be magnified.
+    ///
+    /// This lets the last frame follow panning and zooming until a new one has been rendered.
+    pub(crate) fn placement(&self, controls: &Controls, view: &DisplayedView) -> Option<([f32; 2], f32)> {
+        placement(self.curr_buffer.as_ref()?.user_data(), controls, view)
+    }
+
+    /// Where the previous frame has to be drawn, see `placement`
+    pub(crate) fn previous_placement(&self, controls: &Controls, view: &DisplayedView) -> Option<([f32; 2], f32)> {
+        placement(self.previous_user_data.as_ref()?, controls, view)
+    }
+
+    /// How far the previous frame has faded to the current one, from 0 to 1
+    pub(crate) fn fade(&self, now: Instant) -> f32 {
+        const FADE_DURATION: Duration = Duration::from_millis(150);
+        if self.previous_user_data.is_none() {
+            return 1.0;
+        }
+        ((now - self.received_at).as_secs_f32() / FADE_DURATION.as_secs_f32()).min(1.0)
+    }
+
+    /// Recreate the textures for frames of another size
+    fn resize_texture(
+        &mut self,
+        device: &wgpu::Device,
+        map_bind_group_layout: &wgpu::BindGroupLayout,
+        w: u32, h: u32,
+    ) {
+        let (
+            texture,
+            view,
+            previous_texture,
+            previous_view,
+            sampler,
+            bind_group
+        ) = create_map_texture(device, map_bind_group_layout, w, h);
+        self.texture = texture;
+        self.view = view;
+        self.previous_texture = previous_texture;
+        self.previous_view = previous_view;
+        self.sampler = sampler;
+        self.bind_group = bind_group;
+        self.previous_user_data = None;
+    }
+
+    /// Wait for the renderer thread to finish
+    pub(crate) fn stop(&mut self) -> anyhow::Result<()> {
+        if let Some(handle) = self.map_renderer_join.take() {
+            handle.join()?;
+        }
+        Ok(())
+    }
+
+    /// Whether the current buffer was rendered with the latest styles, at full quality
+    pub(crate) fn is_current(&self) -> bool {
+        self.curr_buffer.as_ref().is_some_and(|buffer| buffer.generation() == self.generation && buffer.quality() == FrameQuality::Full)
+    }
+
+    /// Whether a quick preview is shown while the full quality frame renders
+    pub(crate) fn is_preview(&self) -> bool {
+        self.curr_buffer.as_ref().is_some_and(|buffer| buffer.quality() == FrameQuality::Preview)
+    }
+
+    /// Load the stylesheet again.
+    ///
+    /// When only the styles changed, they are applied to the loaded map, which keeps its
+    /// datasources open. Otherwise the whole map is loaded again on a new renderer thread.
+    pub(crate) fn reload(&mut self, controls: &Controls, basepath: impl AsRef<Path>, static_user_data: &Arc<UserDataStatic>) -> anyhow::Result<()> {
+        let xml = self.map_def.read_xml()?;
+        if let (Some(loaded_xml), Some(join)) = (&self.loaded_xml, &self.map_renderer_join)
+            && only_styles_changed(loaded_xml, &xml)
+        {
+            info!("Only styles changed, updating styles");
+            let styles = styles_only(&xml);
+            let basepath = basepath.as_ref().to_path_buf();
+            join.run(Arc::new(move |map_renderer| {
+                map_renderer.pin_mut().update_styles(&styles, &basepath)?;
+                Ok(())
+            }))?;
+            self.generation += 1;
+            self.loaded_xml = Some(xml);
+            return Ok(());
+        }
+
+        self.restart(controls, basepath, static_user_data)
+    }
+
+    /// Load the stylesheet again on a new renderer thread
+    pub(crate) fn restart(&mut self, controls: &Controls, basepath: impl AsRef<Path>, static_user_data: &Arc<UserDataStatic>) -> anyhow::Result<()> {
+        self.loaded_xml = self.map_def.read_xml().ok();
+        self.generation = 0;
+        let (
+            map_renderer,
+            buffers
+        ) = create_map_renderer((controls.center_x, controls.center_y), &self.map_def, basepath, static_user_data.clone(), self.render_config.as_ref(), &self.proxy)?;
+        self.buffers = buffers;
+        self.curr_buffer = None;
+        self.previous_user_data = None;
+
+        let (join, ud_sender) = map_renderer.start();
+
+        self.map_renderer_join = Some(join);
+        self.ud_sender = ud_sender;
+        self.send_user_data(controls, static_user_data)
+    }
+}
+
+impl Drop for MapLayer {
+    fn drop(&mut self) {
+        self.stop().unwrap();
+    }
+}
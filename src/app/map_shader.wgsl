@@ -1,14 +1,36 @@
 // This shader simply a texture to the screen
 // It requires exactly 6 vertices (draws a rectangle using 2 triangles)
-// The MapDeltaUniform moves the rectangle by the given amounts
+// The MapDeltaUniform moves and scales the texture, so the last frame follows the view until a new one is rendered
+// The textures are rendered larger than the view, the margin is shown when the view moves
+// A new frame fades in over the previous one
+// When comparing two stylesheets, the second texture is composited according to `compare_mode`
+// The frames have premultiplied alpha and are sampled as sRGB, transparent parts show the background
 
 struct VertexOutput {
   @builtin(position) clip_position: vec4<f32>,
-  @location(0) tex_co: vec2<f32>,
+  @location(0) screen_co: vec2<f32>,
 };
 
 struct MapDeltaUniform {
+  // Position of the center of the texture in clip space
   delta: vec2<f32>,
+  compare_delta: vec2<f32>,
+  // 0: no comparison, 1: side by side, 2: swipe, 3: blink (showing the compared map),
+  // 4: difference
+  compare_mode: u32,
+  // Position of the swipe divider, from 0 (left) to 1 (right)
+  swipe: f32,
+  // Opacity of the overlay showing what changed in the last reload
+  reload_diff_alpha: f32,
+  // Magnification of the texture, above 1 when zooming in
+  scale: f32,
+  compare_scale: f32,
+  // How far the previous frame has faded to the current one, from 0 to 1
+  fade: f32,
+  previous_delta: vec2<f32>,
+  previous_scale: f32,
+  // The part of the textures the view covers, the rest is the overscan margin around it
+  extent: vec2<f32>,
 };
 
 @group(1) @binding(0)
@@ -34,8 +56,8 @@ fn vs_main(
   let vertex_idx = in_vertex_index % 6u;
   let pos = rectangle_vertices[vertex_idx];
 
-  out.clip_position = vec4<f32>(pos + map_delta.delta, 0.0, 1.0);
-  out.tex_co = pos * -0.5 + vec2(0.5);
+  out.clip_position = vec4<f32>(pos, 0.0, 1.0);
+  out.screen_co = pos;
   return out;
 }
 
@@ -43,9 +65,100 @@ fn vs_main(
 var map_tex_diffuse: texture_2d<f32>;
 @group(0) @binding(1)
 var map_sampler_diffuse: sampler;
+@group(0) @binding(2)
+var previous_map_tex_diffuse: texture_2d<f32>;
+
+@group(2) @binding(0)
+var compare_tex_diffuse: texture_2d<f32>;
+@group(2) @binding(1)
+var compare_sampler_diffuse: sampler;
+
+@group(3) @binding(0)
+var reload_diff_mask: texture_2d<f32>;
+@group(3) @binding(1)
+var reload_diff_sampler: sampler;
+
+// Pixels that differ by more than this in any channel are highlighted in difference mode
+const difference_threshold: f32 = 0.002;
+const difference_color = vec4<f32>(1.0, 0.0, 0.8, 1.0);
+const reload_diff_color = vec3<f32>(1.0, 0.35, 0.0);
+// Behind transparent parts of the map, the clear color of the window
+const background_color = vec3<f32>(1.0, 1.0, 1.0);
+
+// The window is opaque, whatever alpha mode the surface has
+fn over_background(color: vec4<f32>) -> vec4<f32> {
+  return vec4<f32>(color.rgb + background_color * (1.0 - color.a), 1.0);
+}
+
+// Texture coordinate of a point on the screen (in clip space) for a texture moved by `delta` and magnified by `scale`
+fn tex_co(screen_co: vec2<f32>, delta: vec2<f32>, scale: f32) -> vec2<f32> {
+  let texture_co = (screen_co - delta) / scale * map_delta.extent;
+  // Clip space points up, texture coordinates point down
+  return vec2<f32>(texture_co.x * 0.5 + 0.5, texture_co.y * -0.5 + 0.5);
+}
+
+fn in_texture(co: vec2<f32>) -> bool {
+  return all(co >= vec2(0.0)) && all(co <= vec2(1.0));
+}
 
 @fragment
 fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
-  return textureSample(map_tex_diffuse, map_sampler_diffuse, in.tex_co);
+  var screen_co = in.screen_co;
+  var use_compare = false;
+  switch map_delta.compare_mode {
+    case 1u: {
+      // Each half of the screen shows the middle of its map
+      use_compare = screen_co.x > 0.0;
+      screen_co.x += select(0.5, -0.5, use_compare);
+    }
+    case 2u: {
+      use_compare = screen_co.x * 0.5 + 0.5 > map_delta.swipe;
+    }
+    case 3u: {
+      use_compare = true;
+    }
+    default: {}
+  }
+
+  let co = tex_co(
+    screen_co,
+    select(map_delta.delta, map_delta.compare_delta, use_compare),
+    select(map_delta.scale, map_delta.compare_scale, use_compare)
+  );
+  let previous_co = tex_co(screen_co, map_delta.previous_delta, map_delta.previous_scale);
+  let show_previous = !use_compare && map_delta.compare_mode != 4u && map_delta.fade < 1.0 && in_texture(previous_co);
+  if !in_texture(co) && !show_previous {
+    discard;
+  }
+
+  var map_color = textureSampleLevel(map_tex_diffuse, map_sampler_diffuse, co, 0.0);
+  // The compared map can lag behind at another offset or scale, like the main map
+  let compare_co = tex_co(screen_co, map_delta.compare_delta, map_delta.compare_scale);
+  let compare_color = textureSampleLevel(compare_tex_diffuse, compare_sampler_diffuse, compare_co, 0.0);
+
+  if map_delta.compare_mode == 4u {
+    // Fade out what stayed the same, so the changes stand out. Where the compared map
+    // isn't rendered yet there's nothing to compare with.
+    if in_texture(compare_co) && any(abs(map_color - compare_color) > vec4(difference_threshold)) {
+      return difference_color;
+    }
+    return over_background(vec4<f32>(mix(map_color.rgb, vec3(map_color.a), 0.7), map_color.a));
+  }
+
+  if show_previous {
+    // Where the new frame doesn't reach, the previous frame stays until the fade is done
+    let previous_color = textureSampleLevel(previous_map_tex_diffuse, map_sampler_diffuse, previous_co, 0.0);
+    map_color = select(previous_color, mix(previous_color, map_color, map_delta.fade), in_texture(co));
+  }
+
+  var color = select(map_color, compare_color, use_compare);
+
+  // The mask belongs to the frame that is currently shown
+  let reload_diff = textureSampleLevel(reload_diff_mask, reload_diff_sampler, tex_co(screen_co, map_delta.delta, map_delta.scale), 0.0).r;
+  if !use_compare {
+    color = vec4<f32>(mix(color.rgb, reload_diff_color * color.a, reload_diff * map_delta.reload_diff_alpha * 0.8), color.a);
+  }
+
+  return over_background(color);
  // return vec4<f32>(0.3, 0.2, 0.1, 1.0);
 }
@@ -0,0 +1,232 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use log::*;
+use winit::event_loop::EventLoopProxy;
+
+use crate::{Point, ProjTransformMemberExt as _, ScreenMapRendererBuffers, ScreenMapRendererJoinHandle, Stylesheet, UserDataSender};
+use super::config::{MinimapConfig, RenderConfig};
+use super::controls::Controls;
+use super::AppEvent;
+use super::map_layer::{create_map_renderer, MapUserData, RenderContext, UserDataStatic, FRAME_FORMAT};
+
+/// An overview of the surroundings of the view, rendered by its own renderer at a smaller scale
+pub(crate) struct Minimap {
+    pub(crate) map_def: Stylesheet,
+    /// Whether `map_def` is a separate stylesheet, rather than the one of the main view
+    pub(crate) own_stylesheet: bool,
+    /// Size in pixels
+    pub(crate) w: u32,
+    pub(crate) h: u32,
+    /// How many times more the minimap shows than the main view
+    zoom_out: f64,
+    /// Tiling and workers like the main view, without previews
+    render_config: RenderConfig,
+    texture_id: imgui::TextureId,
+    /// The frame made opaque, for uploading
+    pixels: Vec<u8>,
+    map_renderer_join: Option<ScreenMapRendererJoinHandle<MapUserData>>,
+    ud_sender: UserDataSender<MapUserData>,
+    buffers: ScreenMapRendererBuffers<MapUserData>,
+    /// The view of the frame that is shown
+    curr_user_data: Option<MapUserData>,
+    /// The static user data of the main view this minimap's static user data was made for
+    main_static_user_data: Arc<UserDataStatic>,
+    static_user_data: Arc<UserDataStatic>,
+    /// Offset between the grabbed point and the center of the viewport rectangle, in minimap pixels
+    drag_offset: Option<[f32; 2]>,
+    /// Wakes up the UI when a frame is rendered
+    proxy: EventLoopProxy<AppEvent>,
+}
+
+impl Minimap {
+    pub(crate) fn new(
+        context: &RenderContext,
+        imgui_renderer: &mut imgui_wgpu::Renderer,
+        controls: &Controls,
+        main_map_def: &Stylesheet,
+        main_static_user_data: Arc<UserDataStatic>,
+        config: &MinimapConfig,
+        render_config: &RenderConfig,
+    ) -> anyhow::Result<Self> {
+        let (map_def, own_stylesheet) = match &config.stylesheet {
+            Some(stylesheet) => (Stylesheet::File(stylesheet.clone()), true),
+            None => (main_map_def.clone(), false),
+        };
+        let (w, h) = config.size;
+        let zoom_out = config.zoom_out;
+        // The minimap is uploaded at its full size, and is small enough to render without a preview
+        let render_config = RenderConfig { preview_divisor: 1, ..render_config.clone() };
+        let texture = imgui_wgpu::Texture::new(context.device, imgui_renderer, imgui_wgpu::TextureConfig {
+            size: wgpu::Extent3d {
+                width: w,
+                height: h,
+                depth_or_array_layers: 1,
+            },
+            label: Some("Minimap"),
+            format: Some(FRAME_FORMAT),
+            ..Default::default()
+        });
+        let texture_id = imgui_renderer.textures.insert(texture);
+
+        let static_user_data = Arc::new(UserDataStatic::with_size(controls, w, h, controls.units_per_pixel_scale * zoom_out));
+        let (
+            map_renderer,
+            buffers
+        ) = create_map_renderer((controls.center_x, controls.center_y), &map_def, context.basepath, static_user_data.clone(), Some(&render_config), context.proxy)?;
+        let (join, ud_sender) = map_renderer.start();
+
+        Ok(Self {
+            map_def,
+            own_stylesheet,
+            w, h,
+            zoom_out,
+            render_config,
+            texture_id,
+            pixels: Vec::new(),
+            map_renderer_join: Some(join),
+            ud_sender,
+            buffers,
+            curr_user_data: None,
+            main_static_user_data,
+            static_user_data,
+            drag_offset: None,
+            proxy: context.proxy.clone(),
+        })
+    }
+
+    /// Follow the main view
+    pub(crate) fn send_user_data(&mut self, controls: &Controls, main_static_user_data: &Arc<UserDataStatic>) -> anyhow::Result<()> {
+        if !Arc::ptr_eq(main_static_user_data, &self.main_static_user_data) {
+            self.main_static_user_data = main_static_user_data.clone();
+            self.static_user_data = Arc::new(UserDataStatic::with_size(controls, self.w, self.h, controls.units_per_pixel_scale * self.zoom_out));
+        }
+        self.ud_sender.send((controls.center_x, controls.center_y, self.static_user_data.clone())).map_err(|err| anyhow::format_err!("{}", err))
+    }
+
+    /// Upload the most recently rendered buffer
+    pub(crate) fn update_buffer(&mut self, queue: &wgpu::Queue, imgui_renderer: &imgui_wgpu::Renderer) -> anyhow::Result<()> {
+        if let Some(buffer) = self.buffers.get_buffer() {
+            let Some(texture) = imgui_renderer.textures.get(self.texture_id) else { return Ok(()) };
+            // imgui blends with straight alpha, so the premultiplied frame is put on the background of the map here
+            self.pixels.clear();
+            self.pixels.extend(buffer.buffer()[..(self.w as usize) * (self.h as usize) * 4].chunks_exact(4).flat_map(|bgra| {
+                let background = 255 - bgra[3];
+                [bgra[0].saturating_add(background), bgra[1].saturating_add(background), bgra[2].saturating_add(background), 255]
+            }));
+            texture.write(queue, &self.pixels, self.w, self.h);
+            self.curr_user_data = Some(buffer.user_data().clone());
+        }
+        Ok(())
+    }
+
+    /// Load the stylesheet again on a new renderer thread
+    pub(crate) fn restart(&mut self, controls: &Controls, basepath: impl AsRef<Path>) -> anyhow::Result<()> {
+        self.stop()?;
+        let (
+            map_renderer,
+            buffers
+        ) = create_map_renderer((controls.center_x, controls.center_y), &self.map_def, basepath, self.static_user_data.clone(), Some(&self.render_config), &self.proxy)?;
+        let (join, ud_sender) = map_renderer.start();
+        self.buffers = buffers;
+        self.map_renderer_join = Some(join);
+        self.ud_sender = ud_sender;
+        self.ud_sender.send((controls.center_x, controls.center_y, self.static_user_data.clone())).map_err(|err| anyhow::format_err!("{}", err))
+    }
+
+    /// Wait for the renderer thread to finish
+    pub(crate) fn stop(&mut self) -> anyhow::Result<()> {
+        if let Some(handle) = self.map_renderer_join.take() {
+            handle.join()?;
+        }
+        Ok(())
+    }
+
+    /// Convert a minimap pixel to the output projection
+    fn projected_at(&self, controls: &Controls, pixel: [f32; 2]) -> Option<Point<f64>> {
+        let ud = self.curr_user_data.as_ref()?;
+        let center = controls.proj_transform().forward(&Point::new(ud.0, ud.1)).ok()?;
+        let units_per_pixel = ud.2.units_per_pixel_scale();
+        Some(Point::new(
+            center.x + (pixel[0] as f64 - self.w as f64 / 2.0) * units_per_pixel,
+            center.y + (self.h as f64 / 2.0 - pixel[1] as f64) * units_per_pixel,
+        ))
+    }
+
+    /// The main view as a rectangle in minimap pixels: its center and size
+    fn viewport_rect(&self, controls: &Controls) -> Option<([f32; 2], [f32; 2])> {
+        let ud = self.curr_user_data.as_ref()?;
+        let transform = controls.proj_transform();
+        let center = transform.forward(&Point::new(ud.0, ud.1)).ok()?;
+        let view_center = controls.projected_center().ok()?;
+        let units_per_pixel = ud.2.units_per_pixel_scale();
+        Some((
+            [
+                (self.w as f64 / 2.0 + (view_center.x - center.x) / units_per_pixel) as f32,
+                (self.h as f64 / 2.0 - (view_center.y - center.y) / units_per_pixel) as f32,
+            ],
+            [
+                (controls.map_width as f64 * controls.units_per_pixel_scale / units_per_pixel) as f32,
+                (controls.map_height as f64 * controls.units_per_pixel_scale / units_per_pixel) as f32,
+            ],
+        ))
+    }
+
+    /// Draw the minimap in the bottom right corner, above the status bar.
+    ///
+    /// Returns the new center of the main view in the output projection while the viewport
+    /// rectangle is dragged, and whether the drag ended.
+    pub(crate) fn draw(&mut self, ui: &imgui::Ui, controls: &Controls, bottom_margin: f32) -> (Option<Point<f64>>, bool) {
+        const MARGIN: f32 = 8.0;
+        let [display_w, display_h] = ui.io().display_size;
+        let size = [self.w as f32, self.h as f32];
+        let mut new_center = None;
+        let mut drag_ended = false;
+
+        let _padding = ui.push_style_var(imgui::StyleVar::WindowPadding([0.0, 0.0]));
+        ui.window("Minimap")
+            .position([display_w - size[0] - MARGIN, display_h - size[1] - bottom_margin - MARGIN], imgui::Condition::Always)
+            .content_size(size)
+            .no_decoration()
+            .movable(false)
+            .bg_alpha(0.0)
+            .build(|| {
+                let origin = ui.cursor_screen_pos();
+                imgui::Image::new(self.texture_id, size).build(ui);
+                let mouse = ui.io().mouse_pos;
+                let pixel = [mouse[0] - origin[0], mouse[1] - origin[1]];
+
+                let Some((rect_center, rect_size)) = self.viewport_rect(controls) else { return };
+                // Keep very small viewports visible
+                let half = [(rect_size[0] / 2.0).max(2.0), (rect_size[1] / 2.0).max(2.0)];
+                let min = [origin[0] + rect_center[0] - half[0], origin[1] + rect_center[1] - half[1]];
+                let max = [origin[0] + rect_center[0] + half[0], origin[1] + rect_center[1] + half[1]];
+                let color = if self.drag_offset.is_some() { [1.0, 0.6, 0.0, 1.0] } else { [1.0, 0.0, 0.0, 1.0] };
+                let draw_list = ui.get_window_draw_list();
+                draw_list.add_rect(min, max, [color[0], color[1], color[2], 0.2]).filled(true).build();
+                draw_list.add_rect(min, max, color).thickness(1.5).build();
+
+                if ui.is_item_clicked() {
+                    // Clicking outside of the rectangle moves it there
+                    let inside = mouse[0] >= min[0] && mouse[0] <= max[0] && mouse[1] >= min[1] && mouse[1] <= max[1];
+                    self.drag_offset = Some(if inside { [rect_center[0] - pixel[0], rect_center[1] - pixel[1]] } else { [0.0, 0.0] });
+                }
+                let Some(offset) = self.drag_offset else { return };
+                new_center = self.projected_at(controls, [pixel[0] + offset[0], pixel[1] + offset[1]]);
+                if !ui.is_mouse_down(imgui::MouseButton::Left) {
+                    self.drag_offset = None;
+                    drag_ended = true;
+                }
+            });
+
+        return (new_center, drag_ended);
+    }
+}
+
+impl Drop for Minimap {
+    fn drop(&mut self) {
+        if let Err(err) = self.stop() {
+            error!("Couldn't stop the minimap renderer: {}", err);
+        }
+    }
+}
@@ -2,6 +2,25 @@ mod app;
 pub use app::*;
 pub(crate) mod window;
 pub(crate) mod controls;
+pub(crate) mod map_layer;
+pub(crate) mod compare;
+pub(crate) mod reload_diff;
+pub(crate) mod config;
+pub(crate) mod status_bar;
+pub(crate) mod clipboard;
+pub(crate) mod history;
+pub(crate) mod animation;
+pub(crate) mod minimap;
+pub(crate) mod search;
 
-// Fix until proper moving is implemented
-pub(crate) const TEMP_OFFSET: f32 = 1.5;
+/// Wakes up the event loop, which otherwise sleeps until the next window event
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AppEvent {
+    /// A renderer thread has a new frame
+    NewFrame,
+    /// A stylesheet changed on disk or a new one arrived on stdin
+    StylesheetChanged,
+}
+
+/// Zoom factor of one mouse wheel notch or key press
+pub(crate) const ZOOM_STEP: f64 = 1.25;
@@ -0,0 +1,169 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::*;
+
+use crate::ScreenMapRendererBuffer;
+use super::map_layer::MapUserData;
+
+/// Highlights the pixels that changed when the stylesheet was reloaded.
+///
+/// The last frame before a reload is kept and compared against the first frame of the
+/// reloaded stylesheet. The changed pixels are uploaded as a mask, which `map_shader.wgsl`
+/// draws as a tinted overlay that fades out.
+pub(crate) struct ReloadDiff {
+    pub(crate) texture: wgpu::Texture,
+    pub(crate) bind_group: wgpu::BindGroup,
+    previous_frame: Option<(Vec<u8>, MapUserData)>,
+    /// Number of pixels in the viewport that changed in the last reload
+    pub(crate) changed_pixels: Option<usize>,
+    /// Number of pixels in the viewport of the last reload
+    pub(crate) viewport_pixels: usize,
+    changed_at: Instant,
+    pub(crate) fade_duration: Duration,
+}
+
+impl ReloadDiff {
+    pub(crate) fn new(
+        device: &wgpu::Device,
+        map_bind_group_layout: &wgpu::BindGroupLayout,
+        w: u32, h: u32,
+    ) -> Self {
+        let (texture, bind_group) = create_mask_texture(device, map_bind_group_layout, w, h);
+        Self {
+            texture,
+            bind_group,
+            previous_frame: None,
+            changed_pixels: None,
+            viewport_pixels: 0,
+            changed_at: Instant::now(),
+            fade_duration: Duration::from_secs(3),
+        }
+    }
+
+    pub(crate) fn resize(&mut self, device: &wgpu::Device, map_bind_group_layout: &wgpu::BindGroupLayout, w: u32, h: u32) {
+        *self = Self {
+            fade_duration: self.fade_duration,
+            ..Self::new(device, map_bind_group_layout, w, h)
+        };
+    }
+
+    /// Keep the frame currently on screen, to compare against after reloading
+    pub(crate) fn capture(&mut self, buffer: Option<&ScreenMapRendererBuffer<MapUserData>>) {
+        self.previous_frame = buffer.map(|buffer| (buffer.buffer().to_vec(), buffer.user_data().clone()));
+    }
+
+    /// Compare a newly rendered frame against the captured frame
+    pub(crate) fn compare(&mut self, queue: &wgpu::Queue, buffer: &ScreenMapRendererBuffer<MapUserData>) {
+        let Some((previous, previous_ud)) = self.previous_frame.take() else { return };
+        let ud = buffer.user_data();
+        if ud.0 != previous_ud.0 || ud.1 != previous_ud.1 || !Arc::ptr_eq(&ud.2, &previous_ud.2) {
+            debug!("View changed during reload, not comparing frames");
+            return;
+        }
+        let (w, h) = buffer.size();
+        if (self.texture.width(), self.texture.height()) != (w, h) || previous.len() != buffer.buffer().len() {
+            debug!("Map size changed during reload, not comparing frames");
+            return;
+        }
+
+        let current = buffer.buffer();
+        let mask: Vec<u8> = current.chunks_exact(4)
+            .zip(previous.chunks_exact(4))
+            .map(|(a, b)| if a != b { 255 } else { 0 })
+            .collect();
+        // The overscan margin is highlighted too, but only what's on screen is counted
+        let changed_pixels = ud.2.viewport_pixels(current).chunks_exact(4)
+            .zip(ud.2.viewport_pixels(&previous).chunks_exact(4))
+            .filter(|(a, b)| a != b)
+            .count();
+        let (viewport_w, viewport_h) = ud.2.viewport_size();
+        info!("{} pixels changed", changed_pixels);
+
+        queue.write_texture(
+            wgpu::TexelCopyTextureInfo {
+                texture: &self.texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+                aspect: wgpu::TextureAspect::All,
+            },
+            &mask,
+            wgpu::TexelCopyBufferLayout {
+                offset: 0,
+                bytes_per_row: Some(w),
+                rows_per_image: Some(h)
+            },
+            wgpu::Extent3d {
+                width: w,
+                height: h,
+                depth_or_array_layers: 1
+            }
+        );
+        self.changed_pixels = Some(changed_pixels);
+        self.viewport_pixels = viewport_w as usize * viewport_h as usize;
+        self.changed_at = Instant::now();
+    }
+
+    /// Opacity of the overlay, fading from 1 to 0
+    pub(crate) fn alpha(&self, now: Instant) -> f32 {
+        if self.changed_pixels.is_none() {
+            return 0.0;
+        }
+        let elapsed = (now - self.changed_at).as_secs_f32();
+        return (1.0 - elapsed / self.fade_duration.as_secs_f32().max(0.001)).clamp(0.0, 1.0);
+    }
+}
+
+fn create_mask_texture(
+    device: &wgpu::Device,
+    map_bind_group_layout: &wgpu::BindGroupLayout,
+    w: u32, h: u32,
+) -> (wgpu::Texture, wgpu::BindGroup) {
+    let texture = device.create_texture(&wgpu::TextureDescriptor {
+        size: wgpu::Extent3d {
+            width: w,
+            height: h,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: wgpu::TextureFormat::R8Unorm,
+        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        label: Some("ReloadDiffTexture"),
+        view_formats: &[]
+    });
+    let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+    let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+        address_mode_u: wgpu::AddressMode::ClampToEdge,
+        address_mode_v: wgpu::AddressMode::ClampToEdge,
+        address_mode_w: wgpu::AddressMode::ClampToEdge,
+        mag_filter: wgpu::FilterMode::Linear,
+        min_filter: wgpu::FilterMode::Linear,
+        mipmap_filter: wgpu::FilterMode::Nearest,
+        ..Default::default()
+    });
+
+    // The mask has the same layout as a map texture
+    let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("Reload Diff Bind Group"),
+        layout: map_bind_group_layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(&view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::Sampler(&sampler),
+            },
+            // Unused, there is no previous mask
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: wgpu::BindingResource::TextureView(&view),
+            }
+        ]
+    });
+
+    return (texture, bind_group);
+}
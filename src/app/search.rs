@@ -0,0 +1,382 @@
+use std::fs;
+use std::path::Path;
+use std::sync::LazyLock;
+
+use log::*;
+use regex::Regex;
+
+use crate::Point;
+
+/// Scale denominator of a coordinate without a zoom level
+const POINT_SCALE_DENOMINATOR: f64 = 10_000.0;
+/// Scale denominator of a place without a scale in the gazetteer
+const PLACE_SCALE_DENOMINATOR: f64 = 50_000.0;
+/// Scale denominator of web mercator zoom level 0, with 256 pixel tiles of 0.28 mm pixels
+const ZOOM_0_SCALE_DENOMINATOR: f64 = 559_082_264.028_717_8;
+
+/// A place the view can go to
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Location {
+    pub(crate) name: String,
+    /// `point` is in this projection
+    pub(crate) srs: String,
+    pub(crate) point: Point<f64>,
+    pub(crate) scale_denominator: f64,
+}
+
+/// An angle like `50.85`, `-4.35`, `50.85N` or `50°51'01.2"N`
+const ANGLE: &str = r#"([+-]?\d+(?:\.\d+)?)(?:\s*°(?:\s*(\d+(?:\.\d+)?)\s*['′])?(?:\s*(\d+(?:\.\d+)?)\s*(?:"|″|''))?)?\s*([NSEWnsew])?"#;
+
+static LATLON: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(&format!(r"^{ANGLE}(?:\s*[,;]\s*|\s+){ANGLE}$")).unwrap()
+});
+static SRS_XY: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^(?i)(epsg:\d+)[\s,;]+([+-]?\d+(?:\.\d+)?)(?:\s*[,;]\s*|\s+)([+-]?\d+(?:\.\d+)?)$").unwrap()
+});
+/// `zoom/lat/lon`, as in the `#map=` fragment of web maps
+static ZOOM_LATLON: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^(?:.*#(?:map=)?)?(\d+(?:\.\d+)?)/([+-]?\d+(?:\.\d+)?)/([+-]?\d+(?:\.\d+)?)$").unwrap()
+});
+
+/// Parse coordinates in one of the supported notations
+pub(crate) fn parse_coordinates(query: &str) -> Option<Location> {
+    let query = query.trim();
+
+    if let Some(captures) = SRS_XY.captures(query) {
+        return Some(Location {
+            name: query.to_string(),
+            srs: captures[1].to_lowercase(),
+            point: Point::new(captures[2].parse().ok()?, captures[3].parse().ok()?),
+            scale_denominator: POINT_SCALE_DENOMINATOR,
+        });
+    }
+
+    if let Some(captures) = ZOOM_LATLON.captures(query) {
+        let zoom: f64 = captures[1].parse().ok()?;
+        let (lat, lon): (f64, f64) = (captures[2].parse().ok()?, captures[3].parse().ok()?);
+        if lat.abs() > 90.0 || lon.abs() > 180.0 {
+            return None;
+        }
+        return Some(Location {
+            name: query.to_string(),
+            srs: "epsg:4326".to_string(),
+            point: Point::new(lon, lat),
+            scale_denominator: ZOOM_0_SCALE_DENOMINATOR / 2f64.powf(zoom),
+        });
+    }
+
+    let captures = LATLON.captures(query)?;
+    let angle = |i: usize| -> Option<(f64, Option<char>)> {
+        let degrees: f64 = captures[i].parse().ok()?;
+        let minutes: f64 = captures.get(i + 1).map_or(Ok(0.0), |m| m.as_str().parse()).ok()?;
+        let seconds: f64 = captures.get(i + 2).map_or(Ok(0.0), |m| m.as_str().parse()).ok()?;
+        if minutes >= 60.0 || seconds >= 60.0 {
+            return None;
+        }
+        let hemisphere = captures.get(i + 3).and_then(|m| m.as_str().chars().next()).map(|c| c.to_ascii_uppercase());
+        let value = degrees.abs() + minutes / 60.0 + seconds / 3600.0;
+        let negative = degrees < 0.0 || matches!(hemisphere, Some('S' | 'W'));
+        Some((if negative { -value } else { value }, hemisphere))
+    };
+    let (first, first_hemisphere) = angle(1)?;
+    let (second, second_hemisphere) = angle(5)?;
+
+    // Latitude comes first, unless the hemispheres say otherwise
+    let (lat, lon) = match (first_hemisphere, second_hemisphere) {
+        (Some('E' | 'W'), _) | (_, Some('N' | 'S')) => (second, first),
+        _ => (first, second),
+    };
+    if lat.abs() > 90.0 || lon.abs() > 180.0 {
+        return None;
+    }
+    Some(Location {
+        name: query.to_string(),
+        srs: "epsg:4326".to_string(),
+        point: Point::new(lon, lat),
+        scale_denominator: POINT_SCALE_DENOMINATOR,
+    })
+}
+
+/// Named places, read from a CSV or GeoJSON file
+#[derive(Debug, Default)]
+pub(crate) struct Gazetteer {
+    places: Vec<Location>,
+}
+
+impl Gazetteer {
+    /// Read a GeoJSON file of points with a `name` property (`.geojson`, `.json`), or a CSV file
+    /// with `name`, `lat` and `lon` columns.
+    ///
+    /// Both can have an optional `scale` to show the place at.
+    pub(crate) fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)?;
+        let places = match path.extension().and_then(|ext| ext.to_str()).map(|ext| ext.to_lowercase()).as_deref() {
+            Some("geojson" | "json") => parse_geojson(&contents)?,
+            _ => parse_csv(&contents)?,
+        };
+        info!("Loaded {} places from {}", places.len(), path.display());
+        Ok(Self { places })
+    }
+
+    /// Places whose name matches `query`, names starting with it first
+    pub(crate) fn search(&self, query: &str, limit: usize) -> Vec<&Location> {
+        let query = query.trim().to_lowercase();
+        if query.is_empty() {
+            return Vec::new();
+        }
+        let mut matches: Vec<(bool, &Location)> = self.places.iter()
+            .filter_map(|place| {
+                let name = place.name.to_lowercase();
+                name.contains(&query).then_some((!name.starts_with(&query), place))
+            })
+            .collect();
+        // Stable, so the order of the file is kept otherwise
+        matches.sort_by_key(|(later, _)| *later);
+        matches.into_iter().take(limit).map(|(_, place)| place).collect()
+    }
+}
+
+fn parse_geojson(contents: &str) -> anyhow::Result<Vec<Location>> {
+    let json: serde_json::Value = serde_json::from_str(contents)?;
+    let features = match json["type"].as_str() {
+        Some("FeatureCollection") => json["features"].as_array().cloned().unwrap_or_default(),
+        Some("Feature") => vec![json],
+        _ => return Err(anyhow::format_err!("Expected a GeoJSON FeatureCollection")),
+    };
+
+    let places = features.iter()
+        .filter_map(|feature| {
+            let geometry = &feature["geometry"];
+            if geometry["type"] != "Point" {
+                return None;
+            }
+            let coordinates = geometry["coordinates"].as_array()?;
+            let properties = &feature["properties"];
+            Some(Location {
+                name: properties["name"].as_str()?.to_string(),
+                srs: "epsg:4326".to_string(),
+                point: Point::new(coordinates.first()?.as_f64()?, coordinates.get(1)?.as_f64()?),
+                scale_denominator: properties["scale"].as_f64().unwrap_or(PLACE_SCALE_DENOMINATOR),
+            })
+        })
+        .collect();
+    Ok(places)
+}
+
+fn parse_csv(contents: &str) -> anyhow::Result<Vec<Location>> {
+    let mut lines = contents.lines().filter(|line| !line.trim().is_empty());
+    let header = lines.next().ok_or_else(|| anyhow::format_err!("Empty gazetteer"))?;
+    let delimiter = [';', '\t', ','].into_iter()
+        .find(|delimiter| header.contains(*delimiter))
+        .unwrap_or(',');
+
+    let columns: Vec<String> = split_csv_line(header, delimiter).iter().map(|column| column.trim().to_lowercase()).collect();
+    let column = |names: &[&str]| columns.iter().position(|column| names.contains(&column.as_str()));
+    let (Some(name), Some(lat), Some(lon)) = (
+        column(&["name"]),
+        column(&["lat", "latitude", "y"]),
+        column(&["lon", "lng", "long", "longitude", "x"]),
+    ) else {
+        return Err(anyhow::format_err!("The gazetteer needs name, lat and lon columns, found: {}", header));
+    };
+    let scale = column(&["scale"]);
+
+    let mut places = Vec::new();
+    for (i, line) in lines.enumerate() {
+        let fields = split_csv_line(line, delimiter);
+        let field = |i: usize| fields.get(i).map(|field| field.trim()).unwrap_or("");
+        let (Ok(lat), Ok(lon)) = (field(lat).parse::<f64>(), field(lon).parse::<f64>()) else {
+            warn!("Skipping gazetteer line {}: {}", i + 2, line);
+            continue;
+        };
+        places.push(Location {
+            name: field(name).to_string(),
+            srs: "epsg:4326".to_string(),
+            point: Point::new(lon, lat),
+            scale_denominator: scale.and_then(|scale| field(scale).parse().ok()).unwrap_or(PLACE_SCALE_DENOMINATOR),
+        });
+    }
+    Ok(places)
+}
+
+/// Split a CSV line, fields can be quoted with `"` and contain `""`
+fn split_csv_line(line: &str, delimiter: char) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                fields.last_mut().unwrap().push('"');
+            },
+            '"' => quoted = !quoted,
+            c if c == delimiter && !quoted => fields.push(String::new()),
+            c => fields.last_mut().unwrap().push(c),
+        }
+    }
+    fields
+}
+
+/// The search box, going to coordinates or places in the gazetteer
+pub(crate) struct Search {
+    pub(crate) query: String,
+    gazetteer: Option<Gazetteer>,
+    /// Move the keyboard focus to the search box on the next frame
+    pub(crate) focus: bool,
+}
+
+impl Search {
+    const MAX_RESULTS: usize = 10;
+
+    pub(crate) fn new(gazetteer: Option<Gazetteer>) -> Self {
+        Self { query: String::new(), gazetteer, focus: false }
+    }
+
+    /// Draw the search box and its results, returns the location that was picked
+    pub(crate) fn draw(&mut self, ui: &imgui::Ui) -> Option<Location> {
+        if self.focus {
+            ui.set_keyboard_focus_here();
+            self.focus = false;
+        }
+        let hint = if self.gazetteer.is_some() { "coordinates or place" } else { "coordinates" };
+        let entered = ui.input_text("go to", &mut self.query).hint(hint).enter_returns_true(true).build();
+        if self.query.trim().is_empty() {
+            return None;
+        }
+
+        if let Some(location) = parse_coordinates(&self.query) {
+            if entered {
+                return Some(location);
+            }
+            ui.text_disabled(format!("{} at {:.6}, {:.6}", location.srs, location.point.x, location.point.y));
+            return None;
+        }
+
+        let Some(gazetteer) = &self.gazetteer else {
+            ui.text_disabled("Not a coordinate");
+            return None;
+        };
+        let places = gazetteer.search(&self.query, Self::MAX_RESULTS);
+        if places.is_empty() {
+            ui.text_disabled("No matches");
+            return None;
+        }
+        if entered {
+            return Some(places[0].clone());
+        }
+        let mut picked = None;
+        for (i, place) in places.into_iter().enumerate() {
+            // Places can share a name
+            if ui.selectable(format!("{}##place{}", place.name, i)) {
+                picked = Some(place.clone());
+            }
+        }
+        picked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_lonlat(location: Option<Location>, lon: f64, lat: f64) -> Location {
+        let location = location.expect("coordinates should parse");
+        assert_eq!(location.srs, "epsg:4326");
+        assert!((location.point.x - lon).abs() < 1e-9, "lon {} != {}", location.point.x, lon);
+        assert!((location.point.y - lat).abs() < 1e-9, "lat {} != {}", location.point.y, lat);
+        location
+    }
+
+    #[test]
+    fn parses_decimal_degrees() {
+        assert_eq!(parse_coordinates(" 50.85, 4.35 "), Some(Location {
+            name: "50.85, 4.35".to_string(),
+            srs: "epsg:4326".to_string(),
+            point: Point::new(4.35, 50.85),
+            scale_denominator: POINT_SCALE_DENOMINATOR,
+        }));
+        assert_lonlat(parse_coordinates("-33.87;151.21"), 151.21, -33.87);
+    }
+
+    #[test]
+    fn parses_degrees_minutes_seconds() {
+        assert_lonlat(parse_coordinates(r#"50°51'01.2"N 4°21'E"#), 4.35, 50.0 + 51.0 / 60.0 + 1.2 / 3600.0);
+        assert_lonlat(parse_coordinates("33°52′S, 151°12′E"), 151.2, -(33.0 + 52.0 / 60.0));
+    }
+
+    #[test]
+    fn swaps_lat_and_lon_by_hemisphere() {
+        assert_lonlat(parse_coordinates("4.35E 50.85N"), 4.35, 50.85);
+        assert_lonlat(parse_coordinates("4.35w, 50.85n"), -4.35, 50.85);
+        assert_lonlat(parse_coordinates("50.85 4.35E"), 4.35, 50.85);
+    }
+
+    #[test]
+    fn parses_projected_coordinates() {
+        assert_eq!(parse_coordinates("EPSG:3857 485000, 6591000.5"), Some(Location {
+            name: "EPSG:3857 485000, 6591000.5".to_string(),
+            srs: "epsg:3857".to_string(),
+            point: Point::new(485000.0, 6591000.5),
+            scale_denominator: POINT_SCALE_DENOMINATOR,
+        }));
+    }
+
+    #[test]
+    fn parses_web_map_fragments() {
+        let location = assert_lonlat(parse_coordinates("https://www.openstreetmap.org/#map=12/50.85/4.35"), 4.35, 50.85);
+        assert_eq!(location.scale_denominator, ZOOM_0_SCALE_DENOMINATOR / 4096.0);
+        let location = assert_lonlat(parse_coordinates("#map=0/-10/20"), 20.0, -10.0);
+        assert_eq!(location.scale_denominator, ZOOM_0_SCALE_DENOMINATOR);
+        assert_lonlat(parse_coordinates("3/50.85/4.35"), 4.35, 50.85);
+    }
+
+    #[test]
+    fn rejects_invalid_coordinates() {
+        for query in ["", "Brussels", "91, 0", "0, 181", "-90.5 0", "50°61'N 4°E", "50°30'60\"N 4°E", "#map=3/95/0", "#map=3/0/190", "1 2 3"] {
+            assert_eq!(parse_coordinates(query), None, "{query}");
+        }
+    }
+
+    #[test]
+    fn splits_quoted_csv_fields() {
+        assert_eq!(
+            split_csv_line(r#"Brussels,"Bruxelles, Brussel","He said ""hi""",,1"#, ','),
+            vec!["Brussels", "Bruxelles, Brussel", "He said \"hi\"", "", "1"],
+        );
+        assert_eq!(split_csv_line("a\tb", '\t'), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn reads_csv_gazetteers() {
+        let csv = "Name;Latitude;Lon;Scale\n\"Antwerp; port\";51.22;4.40;25000\nnowhere;x;y;\n\nGhent;51.05;3.72;\n";
+        assert_eq!(parse_csv(csv).unwrap(), vec![
+            Location {
+                name: "Antwerp; port".to_string(),
+                srs: "epsg:4326".to_string(),
+                point: Point::new(4.40, 51.22),
+                scale_denominator: 25000.0,
+            },
+            Location {
+                name: "Ghent".to_string(),
+                srs: "epsg:4326".to_string(),
+                point: Point::new(3.72, 51.05),
+                scale_denominator: PLACE_SCALE_DENOMINATOR,
+            },
+        ]);
+        assert!(parse_csv("name,x\nGhent,3.72\n").is_err());
+    }
+
+    #[test]
+    fn searches_names_starting_with_the_query_first() {
+        let gazetteer = Gazetteer {
+            places: parse_csv("name,lat,lon\nSaint-Gilles,50.83,4.34\nGilly,50.42,4.48\nGhent,51.05,3.72\n").unwrap(),
+        };
+        let names = |places: Vec<&Location>| places.into_iter().map(|place| place.name.clone()).collect::<Vec<_>>();
+        assert_eq!(names(gazetteer.search(" GIL", 10)), vec!["Gilly", "Saint-Gilles"]);
+        assert_eq!(names(gazetteer.search("gil", 1)), vec!["Gilly"]);
+        assert_eq!(names(gazetteer.search("brussels", 10)), Vec::<String>::new());
+        assert!(gazetteer.search("  ", 10).is_empty());
+    }
+}
@@ -0,0 +1,153 @@
+use crate::Point;
+use super::controls::Controls;
+
+/// How coordinates are copied to the clipboard
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CoordinateFormat {
+    /// Latitude and longitude in decimal degrees
+    DecimalDegrees,
+    /// Latitude and longitude in degrees, minutes and seconds
+    Dms,
+    /// Coordinates in the input projection
+    Input,
+    /// Coordinates in the output projection
+    Output,
+}
+
+impl CoordinateFormat {
+    pub(crate) const ALL: [CoordinateFormat; 4] = [CoordinateFormat::DecimalDegrees, CoordinateFormat::Dms, CoordinateFormat::Input, CoordinateFormat::Output];
+
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            CoordinateFormat::DecimalDegrees => "DD",
+            CoordinateFormat::Dms => "DMS",
+            CoordinateFormat::Input => "input",
+            CoordinateFormat::Output => "output",
+        }
+    }
+}
+
+/// The coordinates of the point under the cursor
+#[derive(Debug, Clone)]
+pub(crate) struct CursorReadout {
+    input: Point<f64>,
+    output: Point<f64>,
+    /// Not every projected point has a longitude and latitude
+    lonlat: Option<Point<f64>>,
+}
+
+impl CursorReadout {
+    /// The readout of the map pixel `pixel`
+    pub(crate) fn new(controls: &Controls, pixel: [f64; 2]) -> anyhow::Result<Self> {
+        let output = controls.projected_at(pixel)?;
+        let input = controls.unproject(&output)?;
+        let lonlat = controls.to_lonlat(&output).ok();
+        Ok(Self { input, output, lonlat })
+    }
+
+    pub(crate) fn format(&self, format: CoordinateFormat) -> Option<String> {
+        match format {
+            CoordinateFormat::DecimalDegrees => self.lonlat.map(|p| format!("{:.6}, {:.6}", p.y, p.x)),
+            CoordinateFormat::Dms => self.lonlat.map(|p| format!("{} {}", format_dms(p.y, 'N', 'S'), format_dms(p.x, 'E', 'W'))),
+            CoordinateFormat::Input => Some(format!("{:.3}, {:.3}", self.input.x, self.input.y)),
+            CoordinateFormat::Output => Some(format!("{:.3}, {:.3}", self.output.x, self.output.y)),
+        }
+    }
+}
+
+/// Format an angle like `50°51'01.2"N`
+fn format_dms(degrees: f64, positive: char, negative: char) -> String {
+    // Round once, so 59.96 seconds doesn't end up as 60.0
+    let tenths_of_seconds = (degrees.abs() * 36000.0).round() as u64;
+    // What rounds to 0 is on neither side
+    let hemisphere = if degrees < 0.0 && tenths_of_seconds > 0 { negative } else { positive };
+    let (d, m, s) = (tenths_of_seconds / 36000, tenths_of_seconds / 600 % 60, tenths_of_seconds % 600);
+    format!("{}°{:02}'{:02}.{}\"{}", d, m, s / 10, s % 10, hemisphere)
+}
+
+pub(crate) struct StatusBar {
+    pub(crate) readout: Option<CursorReadout>,
+}
+
+impl StatusBar {
+    pub(crate) fn new() -> Self {
+        Self { readout: None }
+    }
+
+    pub(crate) fn height(ui: &imgui::Ui) -> f32 {
+        ui.frame_height_with_spacing() + 2.0 * ui.clone_style().window_padding[1]
+    }
+
+    /// Draw the status bar along the bottom of the window, `refining` while a preview is shown
+    pub(crate) fn draw(&self, ui: &imgui::Ui, controls: &Controls, refining: bool) {
+        let [display_w, display_h] = ui.io().display_size;
+        let height = Self::height(ui);
+        ui.window("Status")
+            .position([0.0, display_h - height], imgui::Condition::Always)
+            .size([display_w, height], imgui::Condition::Always)
+            .no_decoration()
+            .movable(false)
+            .bg_alpha(0.7)
+            .build(|| {
+                ui.text(format!("1:{:.0}", controls.scale_denominator()));
+                if refining {
+                    ui.same_line();
+                    ui.text_disabled("refining...");
+                }
+                let Some(readout) = &self.readout else { return };
+                for format in [CoordinateFormat::Input, CoordinateFormat::Output, CoordinateFormat::DecimalDegrees] {
+                    if let Some(text) = readout.format(format) {
+                        ui.same_line();
+                        ui.text_disabled("|");
+                        ui.same_line();
+                        ui.text(format!("{}: {}", format.name(), text));
+                    }
+                }
+
+                ui.same_line();
+                ui.text_disabled("| copy:");
+                for format in CoordinateFormat::ALL {
+                    let Some(text) = readout.format(format) else { continue };
+                    ui.same_line();
+                    if ui.small_button(format.name()) {
+                        ui.set_clipboard_text(text);
+                    }
+                }
+            });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_degrees_minutes_seconds() {
+        assert_eq!(format_dms(50.8503333, 'N', 'S'), "50°51'01.2\"N");
+        assert_eq!(format_dms(4.35, 'E', 'W'), "4°21'00.0\"E");
+        assert_eq!(format_dms(151.0 + 12.0 / 60.0 + 5.04 / 3600.0, 'E', 'W'), "151°12'05.0\"E");
+    }
+
+    #[test]
+    fn carries_rounded_seconds() {
+        // 59.96" rounds up to the next minute, and to the next degree
+        assert_eq!(format_dms(10.0 + 30.0 / 60.0 + 59.96 / 3600.0, 'N', 'S'), "10°31'00.0\"N");
+        assert_eq!(format_dms(10.0 + 59.0 / 60.0 + 59.96 / 3600.0, 'N', 'S'), "11°00'00.0\"N");
+        assert_eq!(format_dms(10.0 + 59.0 / 60.0 + 59.94 / 3600.0, 'N', 'S'), "10°59'59.9\"N");
+    }
+
+    #[test]
+    fn formats_negative_hemispheres() {
+        assert_eq!(format_dms(-33.8666667, 'N', 'S'), "33°52'00.0\"S");
+        assert_eq!(format_dms(-0.5, 'E', 'W'), "0°30'00.0\"W");
+        assert_eq!(format_dms(-0.1 / 3600.0, 'E', 'W'), "0°00'00.1\"W");
+    }
+
+    #[test]
+    fn formats_zero_without_a_negative_hemisphere() {
+        assert_eq!(format_dms(0.0, 'N', 'S'), "0°00'00.0\"N");
+        assert_eq!(format_dms(-0.0, 'N', 'S'), "0°00'00.0\"N");
+        assert_eq!(format_dms(-1e-7, 'N', 'S'), "0°00'00.0\"N");
+        assert_eq!(format_dms(-1e-7, 'E', 'W'), "0°00'00.0\"E");
+    }
+}
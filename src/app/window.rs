@@ -1,19 +1,39 @@
 use std::fs::File;
 use std::io::BufReader;
 use std::path::{Path, PathBuf};
-use std::sync::{mpsc, Arc, Mutex};
+use std::sync::Arc;
 use std::time;
 
-use cxx::SharedPtr;
 use imgui_winit_support::WinitPlatform;
 use log::*;
 use wgpu::util::DeviceExt as _;
-use winit::dpi::LogicalSize;
-use winit::event_loop::ActiveEventLoop;
+use winit::dpi::{LogicalSize, PhysicalPosition, PhysicalSize};
+use winit::event_loop::{ActiveEventLoop, EventLoopProxy};
+use winit::keyboard::ModifiersState;
 
-use crate::ext::ResultExt as _;
-use crate::{Box2d, MapRendererMemberExt as _, Point, Projection, ScreenMapRenderer, ScreenMapRendererBuffer, ScreenMapRendererBuffers, ScreenMapRendererJoinHandle};
+use crate::{headless, Point, Stylesheet};
+use super::compare::Compare;
+use super::config::{Action, Config};
 use super::controls::Controls;
+use super::map_layer::{MapLayer, RenderContext, UserDataStatic};
+use super::reload_diff::ReloadDiff;
+use super::clipboard::Clipboard;
+use super::status_bar::{CursorReadout, StatusBar};
+use super::history::{History, View};
+use super::animation::{DisplayedView, ViewAnimation};
+use super::minimap::Minimap;
+use super::search::{Gazetteer, Location, Search};
+use super::AppEvent;
+
+/// Where the window finds the data and keeps its state between sessions
+pub(crate) struct AppPaths {
+    /// Relative paths in stylesheets are resolved against it
+    pub(crate) basepath: PathBuf,
+    /// The imgui window layout
+    pub(crate) inifile: PathBuf,
+    /// The window position, view and history
+    pub(crate) cachefile: PathBuf,
+}
 
 pub(crate) struct ImGuiState {
     pub(crate) context: imgui::Context,
@@ -41,6 +61,9 @@ impl ImGuiState {
         );
 
         context.set_ini_filename(ini_filename);
+        if let Some(clipboard) = Clipboard::new() {
+            context.set_clipboard_backend(clipboard);
+        }
 
         let font_size = (13.0 * hidpi_factor) as f32;
         context.io_mut().font_global_scale = (1.0 / hidpi_factor) as f32;
@@ -73,28 +96,6 @@ impl ImGuiState {
     }
 }
 
-pub(crate) struct UserDataStatic {
-    w: u32, h: u32,
-    input_projection: SharedPtr<Projection>,
-    output_projection: SharedPtr<Projection>,
-    units_per_pixel_scale: f32,
-}
-
-impl UserDataStatic {
-    pub(crate) fn new(controls: &Controls) -> Self {
-        Self {
-            w: controls.map_width,
-            h: controls.map_height,
-            input_projection: controls.input_projection(),
-            output_projection: controls.output_projection(),
-            units_per_pixel_scale: controls.units_per_pixel_scale,
-        }
-    }
-}
-
-unsafe impl Send for UserDataStatic {}
-unsafe impl Sync for UserDataStatic {}
-
 pub(crate) struct MapExplorerWindow {
     pub(crate) device: wgpu::Device,
     pub(crate) queue: wgpu::Queue,
@@ -107,146 +108,76 @@ pub(crate) struct MapExplorerWindow {
     pub(crate) imgui: ImGuiState,
 
     pub(crate) controls: Controls,
-    pub(crate) map_def_file: PathBuf,
     pub(crate) basepath: PathBuf,
-    pub(crate) map_texture: wgpu::Texture,
-    pub(crate) map_view: wgpu::TextureView,
-    pub(crate) map_sampler: wgpu::Sampler,
-    pub(crate) map_bind_group: wgpu::BindGroup,
+    pub(crate) map: MapLayer,
+    /// Second stylesheet rendered in sync with `map`
+    pub(crate) compare_map: Option<MapLayer>,
+    pub(crate) compare: Compare,
+    pub(crate) reload_diff: ReloadDiff,
+    pub(crate) minimap: Option<Minimap>,
     pub(crate) map_bind_group_layout: wgpu::BindGroupLayout,
     pub(crate) map_pipeline: wgpu::RenderPipeline,
-    pub(crate) map_renderer_join: Option<ScreenMapRendererJoinHandle>,
-    pub(crate) ud_sender: mpsc::SyncSender<(f32, f32, Arc<UserDataStatic>)>,
-    pub(crate) buffers: Arc<Mutex<ScreenMapRendererBuffers<2, (f32, f32, Arc<UserDataStatic>)>>>,
-    pub(crate) curr_buffer: Option<ScreenMapRendererBuffer<2, (f32, f32, Arc<UserDataStatic>)>>,
 
     // mouse_delta: (f64, f64),
     pub(crate) map_delta: wgpu::Buffer,
     pub(crate) map_delta_bind_group: wgpu::BindGroup,
-
-    pub(crate) mouse_pressed: bool,
+    map_delta_uniform: MapDeltaUniform,
+
+    pub(crate) show_controls: bool,
+    pub(crate) show_minimap: bool,
+    pub(crate) status_bar: StatusBar,
+    pub(crate) history: History,
+    pub(crate) search: Search,
+    animation: Option<ViewAnimation>,
+    pub(crate) modifiers: ModifiersState,
+    /// Where the zoom box was started, in physical pixels
+    pub(crate) zoom_box_start: Option<PhysicalPosition<f64>>,
+    /// The point in the output projection that is being dragged
+    pub(crate) pan_anchor: Option<Point<f64>>,
+    pub(crate) cursor_position: PhysicalPosition<f64>,
 
     pub(crate) static_user_data: Arc<UserDataStatic>,
-}
-
-fn create_map_texture(
-    device: &wgpu::Device,
-    surface_desc: &wgpu::SurfaceConfiguration,
-    map_bind_group_layout: &wgpu::BindGroupLayout,
-    w: u32, h: u32,
-) -> (
-    wgpu::Texture,
-    wgpu::TextureView,
-    wgpu::Sampler,
-    wgpu::BindGroup,
-) {
-    let map_texture = device.create_texture(&wgpu::TextureDescriptor {
-        // size: wgpu::Extent3d { width: w as u32, height: h as u32, depth_or_array_layers: 1 },
-        size: wgpu::Extent3d {
-            width: w,
-            height: h,
-            depth_or_array_layers: 1,
-        },
-        mip_level_count: 1,
-        sample_count: 1,
-        dimension: wgpu::TextureDimension::D2,
-        format: surface_desc.format,
-        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
-        label: Some("MapTexture"),
-        view_formats: &[]
-    });
-    let map_view = map_texture.create_view(&wgpu::TextureViewDescriptor::default());
-    let map_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
-        address_mode_u: wgpu::AddressMode::ClampToEdge,
-        address_mode_v: wgpu::AddressMode::ClampToEdge,
-        address_mode_w: wgpu::AddressMode::ClampToEdge,
-        mag_filter: wgpu::FilterMode::Linear,
-        min_filter: wgpu::FilterMode::Linear,
-        mipmap_filter: wgpu::FilterMode::Nearest,
-        ..Default::default()
-    });
-
-    let map_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-        label: Some("Map Bind Group"),
-        layout: &map_bind_group_layout,
-        entries: &[
-            wgpu::BindGroupEntry {
-                binding: 0,
-                resource: wgpu::BindingResource::TextureView(&map_view),
-            },
-            wgpu::BindGroupEntry {
-                binding: 1,
-                resource: wgpu::BindingResource::Sampler(&map_sampler),
-            }
-        ]
-    });
-
-    return (map_texture, map_view, map_sampler, map_bind_group);
-}
-
-fn create_map_renderer<const N: usize>(
-    controls: &Controls,
-    map_def_file: impl AsRef<Path>,
-    base_path: impl AsRef<Path>,
-    static_user_data: Arc<UserDataStatic>,
-) -> anyhow::Result<(
-    ScreenMapRenderer<N, (f32, f32, Arc<UserDataStatic>)>,
-    // ScreenMapRendererJoinHandle,
-    // SyncSender<(f32, f32)>,
-    Arc<Mutex<ScreenMapRendererBuffers<N, (f32, f32, Arc<UserDataStatic>)>>>
-)> {
-    // let (map_renderer, buffers) = ScreenMapRenderer::new_from_file(w as u32, h as u32, map_def_file, "./data/build", (controls.center_x, controls.center_y))?;
-    // let c = controls.clone();
-    // let controls = controls.read().anyhow()?;
-    let (map_renderer, buffers) = ScreenMapRenderer::new_from_file(
-        controls.map_width, controls.map_height,
-        map_def_file, base_path,
-        (controls.center_x, controls.center_y, static_user_data),
-        Box::new(|map_renderer, ud| {
-            let u: &Arc<UserDataStatic> = &ud.2;
-            let bbox = Box2d::<f64>::new_centered(
-                &Point::<f64>::new(ud.0 as f64, ud.1 as f64),
-                u.input_projection.clone(),
-                u.output_projection.clone(),
-                u.units_per_pixel_scale.into(),
-                u.w,
-                u.h
-            );
-            map_renderer.pin_mut()
-                .zoom_to_box(&bbox);
-        })
-    )?;
-    #[allow(deprecated)] // TODO
-    let map_renderer_and_ud = map_renderer.map_renderer_and_user_data();
-    let bbox = controls.create_center_box(controls.map_width, controls.map_height); // TODO: replace with an on_receive user data callback
-    map_renderer_and_ud.lock()
-        .anyhow()?
-        .map_renderer_mut()
-        .pin_mut()
-        .zoom_to_box(&bbox);
-
-    Ok((
-        map_renderer,
-        buffers
-    ))
+    /// Pixels rendered beyond each edge of the map
+    pub(crate) overscan: u32,
+    /// Map pixels per window pixel, below 1 renders faster and blurrier
+    pub(crate) render_scale: f64,
+    /// Frames still to draw after input, imgui takes a few to settle
+    pub(crate) settle_frames: u32,
 }
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 struct MapDeltaUniform {
     delta: [f32; 2],
+    compare_delta: [f32; 2],
+    compare_mode: u32,
+    swipe: f32,
+    reload_diff_alpha: f32,
+    scale: f32,
+    compare_scale: f32,
+    /// How far the previous frame has faded to the current one
+    fade: f32,
+    previous_delta: [f32; 2],
+    previous_scale: f32,
+    _padding: f32,
+    /// The part of the textures the view covers, the rest is the overscan margin
+    extent: [f32; 2],
 }
 
 impl MapExplorerWindow {
+    /// Frames drawn after every input event
+    pub(crate) const SETTLE_FRAMES: u32 = 2;
+
     pub(crate) async fn new(
-        w: usize, h: usize,
+        (w, h): (usize, usize),
         event_loop: &ActiveEventLoop,
-        map_def_file: impl AsRef<Path>, basepath: impl AsRef<Path>,
-        inifilename: impl AsRef<Path>,
-        cachefile: impl AsRef<Path>
+        map_def: &Stylesheet, compare_map_def: Option<&Stylesheet>,
+        paths: &AppPaths,
+        config: &Config,
+        proxy: &EventLoopProxy<AppEvent>,
     ) -> anyhow::Result<Self> {
-        let mut cache: Option<serde_json::Value> = if cachefile.as_ref().exists() {
-            let cachefile = File::open(cachefile.as_ref())?;
+        let mut cache: Option<serde_json::Value> = if paths.cachefile.exists() {
+            let cachefile = File::open(&paths.cachefile)?;
             let cachereader = BufReader::new(cachefile);
             let json = serde_json::from_reader(cachereader)?;
             info!("Cache: {}", json);
@@ -267,7 +198,6 @@ impl MapExplorerWindow {
                 .with_title("MapExplorer");
             Arc::new(event_loop.create_window(attributes)?)
         };
-        window.set_resizable(false);
         'wincache: { // restore window position
             let Some(cache) = &mut cache else { break 'wincache };
             let obj = cache.as_object_mut().unwrap();
@@ -301,8 +231,8 @@ impl MapExplorerWindow {
 
         surface.configure(&device, &surface_desc);
 
-        let imgui = ImGuiState::new(
-            Some(inifilename.as_ref().to_path_buf()),
+        let mut imgui = ImGuiState::new(
+            Some(paths.inifile.clone()),
             window.clone(),
             hidpi_factor,
             surface_desc.format,
@@ -317,13 +247,42 @@ impl MapExplorerWindow {
             Controls::from_json(controls.take())?
         };
 
-        let static_user_data = Arc::new(UserDataStatic::new(&controls));
+        let history = 'historycache: {
+            let Some(cache) = &mut cache else { break 'historycache History::new(View::of(&controls)) };
+            let Some(obj) = cache.as_object_mut() else { break 'historycache History::new(View::of(&controls)) };
+            let Some(history) = obj.get_mut("history") else { break 'historycache History::new(View::of(&controls)) };
+            match History::from_json(history.take(), View::of(&controls)) {
+                Ok(history) => history,
+                Err(err) => {
+                    warn!("Couldn't restore the navigation history: {}", err);
+                    History::new(View::of(&controls))
+                },
+            }
+        };
+
+        let static_user_data = Arc::new(UserDataStatic::new(&controls, config.render.overscan));
+
+        let gazetteer = match &config.gazetteer {
+            Some(path) => match Gazetteer::load(path) {
+                Ok(gazetteer) => Some(gazetteer),
+                Err(err) => {
+                    warn!("Couldn't load the gazetteer {}: {}", path.display(), err);
+                    None
+                },
+            },
+            None => None,
+        };
 
-        let (
-            map_renderer,
-            buffers
-        ) = create_map_renderer(&controls, map_def_file.as_ref(), basepath.as_ref(), static_user_data.clone())?;
-        let (join, ud_sender) = map_renderer.start();
+        let render_context = RenderContext {
+            device: &device,
+            basepath: &paths.basepath,
+            proxy,
+        };
+        let minimap = if config.minimap.enabled {
+            Some(Minimap::new(&render_context, &mut imgui.renderer, &controls, map_def, static_user_data.clone(), &config.minimap, &config.render)?)
+        } else {
+            None
+        };
 
         let map_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
             label: Some("Map Bind Group Layout"),
@@ -346,19 +305,47 @@ impl MapExplorerWindow {
                     ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
                     count: None,
                 },
+                // The previous frame, sampled with the same sampler
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Float {
+                            filterable: true
+                        },
+                    },
+                    count: None,
+                },
             ],
         });
 
-        let (
-            map_texture,
-            map_view,
-            map_sampler,
-            map_bind_group
-        ) = create_map_texture(&device, &surface_desc, &map_bind_group_layout, controls.map_width, controls.map_height);
+        let map = MapLayer::new(&render_context, &map_bind_group_layout, &controls, map_def.clone(), static_user_data.clone(), Some(config.render.clone()))?;
+        let compare_map = match compare_map_def {
+            Some(compare_map_def) => Some(MapLayer::new(&render_context, &map_bind_group_layout, &controls, compare_map_def.clone(), static_user_data.clone(), Some(config.render.clone()))?),
+            None => None,
+        };
 
+        let map_delta_uniform = MapDeltaUniform {
+            delta: [0., 0.],
+            compare_delta: [0., 0.],
+            compare_mode: 0,
+            swipe: 0.5,
+            reload_diff_alpha: 0.,
+            scale: 1.,
+            compare_scale: 1.,
+            fade: 1.,
+            previous_delta: [0., 0.],
+            previous_scale: 1.,
+            _padding: 0.,
+            extent: [1., 1.],
+        };
+        let (rendered_w, rendered_h) = static_user_data.rendered_size();
+        let reload_diff = ReloadDiff::new(&device, &map_bind_group_layout, rendered_w, rendered_h);
         let map_delta = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("Map Delta Uniform Buffer"),
-            contents: bytemuck::cast_slice(&[MapDeltaUniform { delta: [0., 0.] }]),
+            contents: bytemuck::cast_slice(&[map_delta_uniform]),
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
         });
         let map_delta_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
@@ -366,7 +353,7 @@ impl MapExplorerWindow {
             entries: &[
                 wgpu::BindGroupLayoutEntry {
                     binding: 0,
-                    visibility: wgpu::ShaderStages::VERTEX,
+                    visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
                     ty: wgpu::BindingType::Buffer {
                         ty: wgpu::BufferBindingType::Uniform,
                         has_dynamic_offset: false,
@@ -392,6 +379,8 @@ impl MapExplorerWindow {
             bind_group_layouts: &[
                 &map_bind_group_layout,
                 &map_delta_bind_group_layout,
+                &map_bind_group_layout, // compared map
+                &map_bind_group_layout, // reload diff mask
             ],
             push_constant_ranges: &[],
         });
@@ -444,136 +433,393 @@ impl MapExplorerWindow {
             imgui,
 
             controls,
-            map_def_file: map_def_file.as_ref().to_path_buf(),
-            basepath: basepath.as_ref().to_path_buf(),
-            map_texture,
-            map_view,
-            map_sampler,
+            basepath: paths.basepath.clone(),
+            map,
+            compare_map,
+            compare: Compare::new(),
+            reload_diff,
+            minimap,
             map_bind_group_layout,
-            map_bind_group,
             map_pipeline,
-            map_renderer_join: Some(join),
-            ud_sender,
-            buffers,
-            curr_buffer: None,
 
             map_delta,
             map_delta_bind_group,
-
-            mouse_pressed: false,
-            static_user_data
+            map_delta_uniform,
+
+            show_controls: true,
+            show_minimap: true,
+            status_bar: StatusBar::new(),
+            history,
+            search: Search::new(gazetteer),
+            animation: None,
+            modifiers: ModifiersState::empty(),
+            zoom_box_start: None,
+            pan_anchor: None,
+            cursor_position: PhysicalPosition::new(0., 0.),
+            static_user_data,
+            overscan: config.render.overscan,
+            render_scale: 1.0,
+            settle_frames: 0,
         })
     }
 
+    /// Send the current view to the renderer threads
+    pub(crate) fn send_user_data(&mut self) -> anyhow::Result<()> {
+        self.map.send_user_data(&self.controls, &self.static_user_data)?;
+        if let Some(compare_map) = &self.compare_map {
+            compare_map.send_user_data(&self.controls, &self.static_user_data)?;
+        }
+        if let Some(minimap) = &mut self.minimap {
+            minimap.send_user_data(&self.controls, &self.static_user_data)?;
+        }
+        Ok(())
+    }
+
     pub(crate) fn update_buffer(&mut self) -> anyhow::Result<()> {
-        match self.buffers.try_lock() {
-            Ok(mut buffers) => {
-                if let Some(buffer) = buffers.get_buffer() {
-                    self.queue.write_texture(
-                        wgpu::TexelCopyTextureInfo {
-                            texture: &self.map_texture,
-                            mip_level: 0,
-                            origin: wgpu::Origin3d::ZERO,
-                            aspect: wgpu::TextureAspect::All,
-                        },
-                        buffer.buffer(),
-                        wgpu::TexelCopyBufferLayout {
-                            offset: 0,
-                            bytes_per_row: Some(self.controls.map_width * 4),
-                            rows_per_image: Some(self.controls.map_height)
-                        },
-                        wgpu::Extent3d {
-                            width: self.controls.map_width,
-                            height: self.controls.map_height,
-                            depth_or_array_layers: 1
-                        }
-                    );
-                    self.curr_buffer = Some(buffer);
-                }
+        let now = time::Instant::now();
+        if self.map.update_buffer(&self.device, &self.queue, &self.map_bind_group_layout)? && self.map.is_current() {
+            self.reload_diff.compare(&self.queue, self.map.curr_buffer.as_ref().unwrap());
+        }
+        if let Some(extent) = self.map.extent(&self.controls) {
+            self.map_delta_uniform.extent = extent;
+        }
+        self.map_delta_uniform.reload_diff_alpha = self.reload_diff.alpha(now);
+        let view = self.displayed_view(now);
+        if let Some((delta, scale)) = view.and_then(|view| self.map.placement(&self.controls, &view)) {
+            self.map_delta_uniform.delta = delta;
+            self.map_delta_uniform.scale = scale;
+        }
+        self.map_delta_uniform.fade = self.map.fade(now);
+        if let Some((delta, scale)) = view.and_then(|view| self.map.previous_placement(&self.controls, &view)) {
+            self.map_delta_uniform.previous_delta = delta;
+            self.map_delta_uniform.previous_scale = scale;
+        }
 
-                if let Some(buffer) = &self.curr_buffer {
-                    let ud = buffer.user_data();
-                    // TODO: proj_transform
-                    let delta = [
-                        ((self.controls.center_x - ud.0) / (self.controls.map_width as f32)) / (self.hidpi_factor as f32 * super::TEMP_OFFSET),
-                        (-1. * (self.controls.center_y - ud.1) / (self.controls.map_height as f32)) / (self.hidpi_factor as f32 * super::TEMP_OFFSET)
-                    ];
-                    self.queue.write_buffer(&self.map_delta, 0, bytemuck::cast_slice(&[MapDeltaUniform { delta }]));
-                }
+        if let Some(compare_map) = &mut self.compare_map {
+            compare_map.update_buffer(&self.device, &self.queue, &self.map_bind_group_layout)?;
+            if let Some((delta, scale)) = view.and_then(|view| compare_map.placement(&self.controls, &view)) {
+                self.map_delta_uniform.compare_delta = delta;
+                self.map_delta_uniform.compare_scale = scale;
+            }
+            self.compare.update(now);
+            self.map_delta_uniform.compare_mode = self.compare.shader_mode();
+            self.map_delta_uniform.swipe = self.compare.swipe;
+        } else {
+            self.map_delta_uniform.compare_mode = 0;
+        }
+
+        self.queue.write_buffer(&self.map_delta, 0, bytemuck::cast_slice(&[self.map_delta_uniform]));
+
+        if let Some(minimap) = &mut self.minimap {
+            minimap.update_buffer(&self.queue, &self.imgui.renderer)?;
+        }
+
+        Ok(())
+    }
+
+    /// When the window has to be drawn again without new events, `None` when it's idle
+    pub(crate) fn next_redraw(&mut self, now: time::Instant) -> Option<time::Instant> {
+        // How often the text cursor of imgui blinks
+        const CURSOR_BLINK: time::Duration = time::Duration::from_millis(400);
+        let animating = self.settle_frames > 0
+            || self.animation.is_some()
+            || self.map.fade(now) < 1.0
+            || self.compare_map.as_ref().is_some_and(|compare_map| compare_map.fade(now) < 1.0)
+            || self.reload_diff.alpha(now) > 0.0;
+        if animating {
+            self.settle_frames = self.settle_frames.saturating_sub(1);
+            return Some(now);
+        }
+        let cursor_blink = self.imgui.context.io().want_text_input.then(|| now + CURSOR_BLINK);
+        let blink = self.compare_map.as_ref().and_then(|_| self.compare.next_blink());
+        cursor_blink.into_iter().chain(blink).min()
+    }
+
+    /// The cursor position in map pixels
+    pub(crate) fn cursor_map_pixel(&self) -> Option<[f64; 2]> {
+        self.map_pixel(self.cursor_position)
+    }
+
+    /// Convert a position in the window to map pixels
+    fn map_pixel(&self, position: PhysicalPosition<f64>) -> Option<[f64; 2]> {
+        let size = self.window.inner_size();
+        if size.width == 0 || size.height == 0 {
+            return None;
+        }
+        // The map texture is stretched over the whole window
+        Some([
+            position.x * self.controls.map_width as f64 / size.width as f64,
+            position.y * self.controls.map_height as f64 / size.height as f64,
+        ])
+    }
+
+    /// Zoom by `factor` (below 1 zooms in), keeping the map point under the cursor in place
+    pub(crate) fn zoom_at_cursor(&mut self, factor: f64) -> anyhow::Result<()> {
+        let Some(anchor) = self.cursor_map_pixel() else { return Ok(()) };
+        self.zoom_at(factor, anchor)?;
+        self.commit_view(true);
+        Ok(())
+    }
+
+    /// Add the current view to the navigation history, `merge` for continuous changes
+    pub(crate) fn commit_view(&mut self, merge: bool) {
+        self.history.commit(View::of(&self.controls), merge);
+    }
+
+    /// Center the view on a search result, at the scale it suggests
+    pub(crate) fn go_to(&mut self, location: &Location) -> anyhow::Result<()> {
+        info!("Going to {}", location.name);
+        let projected = self.controls.project_from(&location.srs, &location.point)?;
+        self.animate_view(|controls| {
+            controls.set_scale_denominator(location.scale_denominator);
+            let center = [controls.map_width as f64 / 2.0, controls.map_height as f64 / 2.0];
+            controls.move_to(projected, center)
+        })?;
+        self.commit_view(false);
+        Ok(())
+    }
+
+    /// Go back in the navigation history
+    pub(crate) fn go_back(&mut self) -> anyhow::Result<()> {
+        let Some(view) = self.history.back().cloned() else { return Ok(()) };
+        self.animate_view(|controls| view.apply(controls))
+    }
+
+    /// Go forward in the navigation history
+    pub(crate) fn go_forward(&mut self) -> anyhow::Result<()> {
+        let Some(view) = self.history.forward().cloned() else { return Ok(()) };
+        self.animate_view(|controls| view.apply(controls))
+    }
+
+    /// The view on screen, which lags behind `controls` while animating
+    fn displayed_view(&mut self, now: time::Instant) -> Option<DisplayedView> {
+        if let Some(animation) = &self.animation {
+            if !animation.finished(now) {
+                return Some(animation.at(now));
+            }
+            self.animation = None;
+        }
+        DisplayedView::of(&self.controls).ok()
+    }
+
+    /// Change the view, animating from the view on screen to the new one
+    fn animate_view(&mut self, change: impl FnOnce(&mut Controls) -> anyhow::Result<()>) -> anyhow::Result<()> {
+        let from = self.displayed_view(time::Instant::now());
+        change(&mut self.controls)?;
+        self.animation = from.zip(DisplayedView::of(&self.controls).ok())
+            .map(|(from, to)| ViewAnimation::new(from, to));
+        self.view_changed()
+    }
+
+    /// Render the view after the scale or projection changed
+    fn view_changed(&mut self) -> anyhow::Result<()> {
+        self.static_user_data = Arc::new(UserDataStatic::new(&self.controls, self.overscan));
+        self.send_user_data()
+    }
+
+    fn zoom_at(&mut self, factor: f64, anchor: [f64; 2]) -> anyhow::Result<()> {
+        self.animate_view(|controls| controls.zoom_at(factor, anchor))
+    }
+
+    fn pan(&mut self, dx: f64, dy: f64) -> anyhow::Result<()> {
+        self.animate_view(|controls| controls.pan(dx, dy))
+    }
+
+    /// Run an action triggered by a key binding
+    pub(crate) fn run_action(&mut self, action: Action, config: &Config) -> anyhow::Result<()> {
+        // Fraction of the map moved by one key press
+        const PAN_STEP: f64 = 0.125;
+        let pan_x = self.controls.map_width as f64 * PAN_STEP;
+        let pan_y = self.controls.map_height as f64 * PAN_STEP;
+        let center = [self.controls.map_width as f64 / 2.0, self.controls.map_height as f64 / 2.0];
+        match action {
+            Action::PanLeft => self.pan(-pan_x, 0.0)?,
+            Action::PanRight => self.pan(pan_x, 0.0)?,
+            Action::PanUp => self.pan(0.0, -pan_y)?,
+            Action::PanDown => self.pan(0.0, pan_y)?,
+            Action::ZoomIn => self.zoom_at(1.0 / super::ZOOM_STEP, center)?,
+            Action::ZoomOut => self.zoom_at(super::ZOOM_STEP, center)?,
+            Action::Reload => return self.reload_map(),
+            Action::Screenshot => return self.screenshot(config.screenshot_dir.as_deref().unwrap_or(Path::new("."))),
+            Action::ToggleControls => {
+                self.show_controls = !self.show_controls;
+                return Ok(());
             },
-            Err(err) => match err {
-                std::sync::TryLockError::Poisoned(poison_error) => return Err(anyhow::format_err!("{}", poison_error)),
-                std::sync::TryLockError::WouldBlock => {},
+            Action::Back => return self.go_back(),
+            Action::Forward => return self.go_forward(),
+            Action::Search => {
+                self.show_controls = true;
+                self.search.focus = true;
+                return Ok(());
             },
         }
+        // Holding a key down is a single change, like scrolling
+        self.commit_view(true);
+        Ok(())
+    }
 
+    /// Save the frame currently shown to a PNG file in `dir`
+    pub(crate) fn screenshot(&self, dir: &Path) -> anyhow::Result<()> {
+        let Some(buffer) = &self.map.curr_buffer else {
+            return Err(anyhow::format_err!("No frame rendered yet"));
+        };
+        let timestamp = time::SystemTime::now().duration_since(time::UNIX_EPOCH)?.as_millis();
+        let output = dir.join(format!("map-explorer-{}.png", timestamp));
+        // The overscan margin isn't on screen
+        let static_user_data = &buffer.user_data().2;
+        let pixels = static_user_data.viewport_pixels(buffer.buffer());
+        let (w, h) = static_user_data.viewport_size();
+        headless::write_buffer_png(&pixels, w, h, &output)?;
+        info!("Saved screenshot to {}", output.display());
         Ok(())
     }
 
-    // TODO: very buggy
-    pub(crate) fn resize_map(&mut self, w: u32, h: u32) -> Result<(), ResizeMapResult> {
-        if w == 0 || h == 0 {
-            return Err(ResizeMapResult::Size0);
+    /// Show the coordinates under the cursor, unless it is over an imgui window
+    pub(crate) fn update_cursor_readout(&mut self) {
+        if self.imgui.context.io().want_capture_mouse {
+            return;
         }
+        self.status_bar.readout = self.cursor_map_pixel()
+            .and_then(|pixel| CursorReadout::new(&self.controls, pixel).ok());
+    }
 
-        if let Some(handle) = self.map_renderer_join.take() {
-            handle.join()?;
+    /// Fit the view to the zoom box that ends at the cursor
+    pub(crate) fn finish_zoom_box(&mut self, zoom_out: bool) -> anyhow::Result<()> {
+        // Smaller boxes are most likely accidental clicks
+        const MIN_SIZE: f64 = 4.0;
+        let Some(start) = self.zoom_box_start.take() else { return Ok(()) };
+        let end = self.cursor_position;
+        if (end.x - start.x).abs() < MIN_SIZE || (end.y - start.y).abs() < MIN_SIZE {
+            return Ok(());
         }
+        let (Some(start), Some(end)) = (self.map_pixel(start), self.map_pixel(end)) else { return Ok(()) };
 
-        let (
-            map_renderer,
-            buffers
-        ) = create_map_renderer(&self.controls, &self.map_def_file, &self.basepath, self.static_user_data.clone())?;
-        self.buffers = buffers;
-        self.curr_buffer = None;
+        self.animate_view(|controls| controls.fit_box(start, end, zoom_out))?;
+        self.commit_view(false);
+        Ok(())
+    }
+
+    /// Stop dragging the map
+    pub(crate) fn finish_pan(&mut self) {
+        if self.pan_anchor.take().is_some() {
+            self.commit_view(false);
+        }
+    }
+
+    /// Start dragging the map, the point under the cursor will follow it
+    pub(crate) fn start_pan(&mut self) -> anyhow::Result<()> {
+        let Some(pixel) = self.cursor_map_pixel() else { return Ok(()) };
+        // Dragging follows the cursor directly
+        self.animation = None;
+        self.pan_anchor = Some(self.controls.projected_at(pixel)?);
+        Ok(())
+    }
+
+    /// Move the grabbed point under the cursor
+    pub(crate) fn pan_to_cursor(&mut self) -> anyhow::Result<()> {
+        let Some(anchor) = self.pan_anchor else { return Ok(()) };
+        let Some(pixel) = self.cursor_map_pixel() else { return Ok(()) };
+        self.controls.move_to(anchor, pixel)?;
+        self.send_user_data()
+    }
+
+    /// Center the view on a point in the output projection, while the minimap is dragged
+    pub(crate) fn center_on(&mut self, projected: Point<f64>) -> anyhow::Result<()> {
+        self.animation = None;
+        let center = [self.controls.map_width as f64 / 2.0, self.controls.map_height as f64 / 2.0];
+        self.controls.move_to(projected, center)?;
+        self.send_user_data()
+    }
+
+    /// Render the map at `w`×`h` pixels, keeping the renderer threads and the view
+    pub(crate) fn resize_map(&mut self, w: u32, h: u32) -> Result<(), ResizeMapResult> {
+        if w == 0 || h == 0 {
+            return Err(ResizeMapResult::Size0);
+        }
 
         let limits = self.device.limits();
-        if w > limits.max_texture_dimension_2d || h > limits.max_texture_dimension_2d {
+        let (rendered_w, rendered_h) = (w + 2 * self.overscan, h + 2 * self.overscan);
+        if rendered_w > limits.max_texture_dimension_2d || rendered_h > limits.max_texture_dimension_2d {
             return Err(ResizeMapResult::SizeTooBig);
         }
+        if (w, h) == (self.controls.map_width, self.controls.map_height) {
+            return Ok(());
+        }
 
         self.controls.map_width = w; // TODO: restrict pub access to map_width
         self.controls.map_height = h;
-        self.static_user_data = Arc::new(UserDataStatic::new(&self.controls));
+        self.static_user_data = Arc::new(UserDataStatic::new(&self.controls, self.overscan));
 
-        let (
-            map_texture,
-            map_view,
-            map_sampler,
-            map_bind_group
-        ) = create_map_texture(&self.device, &self.surface_desc, &self.map_bind_group_layout, self.controls.map_width, self.controls.map_height);
-        self.map_texture = map_texture;
-        self.map_view = map_view;
-        self.map_sampler = map_sampler;
-        self.map_bind_group = map_bind_group;
+        self.reload_diff.resize(&self.device, &self.map_bind_group_layout, rendered_w, rendered_h);
+        // The layers recreate their textures when the first frame of the new size arrives
+        self.map.resize(&self.controls, &self.static_user_data)?;
+        if let Some(compare_map) = &self.compare_map {
+            compare_map.resize(&self.controls, &self.static_user_data)?;
+        }
+        if let Some(minimap) = &mut self.minimap {
+            minimap.send_user_data(&self.controls, &self.static_user_data)?;
+        }
 
-        let (join, ud_sender) = map_renderer.start();
+        Ok(())
+    }
 
-        self.map_renderer_join = Some(join);
-        self.ud_sender = ud_sender;
-        self.ud_sender.send((self.controls.center_x, self.controls.center_y, self.static_user_data.clone())).map_err(|err| anyhow::format_err!("{}", err))?;
+    /// Follow a new window size: the swap chain and the map, at the render scale
+    pub(crate) fn resize_window(&mut self, size: PhysicalSize<u32>) -> Result<(), ResizeMapResult> {
+        if size.width == 0 || size.height == 0 {
+            // Minimized
+            return Err(ResizeMapResult::Size0);
+        }
+        self.surface_desc.width = size.width;
+        self.surface_desc.height = size.height;
+        self.surface.configure(&self.device, &self.surface_desc);
+        let (w, h) = scaled_map_size(size, self.render_scale);
+        self.resize_map(w, h)
+    }
 
+    pub(crate) const MIN_RENDER_SCALE: f64 = 0.25;
+    pub(crate) const MAX_RENDER_SCALE: f64 = 2.0;
+
+    /// Render the map at `render_scale` times the window size, keeping what is shown
+    pub(crate) fn set_render_scale(&mut self, render_scale: f64) -> Result<(), ResizeMapResult> {
+        let render_scale = render_scale.clamp(Self::MIN_RENDER_SCALE, Self::MAX_RENDER_SCALE);
+        let (w, h) = scaled_map_size(self.window.inner_size(), render_scale);
+        // More pixels over the same area
+        let units_per_pixel_scale = self.controls.units_per_pixel_scale;
+        self.controls.units_per_pixel_scale *= self.controls.map_width as f64 / w as f64;
+        if let Err(err) = self.resize_map(w, h) {
+            self.controls.units_per_pixel_scale = units_per_pixel_scale;
+            return Err(err);
+        }
+        self.render_scale = render_scale;
         Ok(())
     }
 
     pub(crate) fn reload_map(&mut self) -> anyhow::Result<()> {
         info!("Reloading map...");
-
-        let (
-            map_renderer,
-            buffers
-        ) = create_map_renderer(&self.controls, &self.map_def_file, &self.basepath, self.static_user_data.clone())?;
-        self.buffers = buffers;
-        self.curr_buffer = None;
-
-        let (join, ud_sender) = map_renderer.start();
-
-        self.map_renderer_join = Some(join);
-        self.ud_sender = ud_sender;
-        self.ud_sender.send((self.controls.center_x, self.controls.center_y, self.static_user_data.clone())).map_err(|err| anyhow::format_err!("{}", err))?;
-
+        self.reload_diff.capture(self.map.curr_buffer.as_ref());
+        self.map.reload(&self.controls, &self.basepath, &self.static_user_data)?;
+        if let Some(minimap) = &mut self.minimap
+            && !minimap.own_stylesheet
+        {
+            minimap.map_def = self.map.map_def.clone();
+            minimap.restart(&self.controls, &self.basepath)?;
+        }
         Ok(())
     }
+
+    pub(crate) fn reload_compare_map(&mut self) -> anyhow::Result<()> {
+        let Some(compare_map) = &mut self.compare_map else { return Ok(()) };
+        info!("Reloading compared map...");
+        compare_map.reload(&self.controls, &self.basepath, &self.static_user_data)
+    }
+}
+
+/// The map size for a window size at a render scale
+fn scaled_map_size(size: PhysicalSize<u32>, render_scale: f64) -> (u32, u32) {
+    (
+        ((size.width as f64 * render_scale).round() as u32).max(1),
+        ((size.height as f64 * render_scale).round() as u32).max(1),
+    )
 }
 
 #[derive(Debug)]
@@ -588,11 +834,3 @@ impl From<anyhow::Error> for ResizeMapResult {
         Self::Error(value)
     }
 }
-
-impl Drop for MapExplorerWindow {
-    fn drop(&mut self) {
-        if let Some(handle) = self.map_renderer_join.take() {
-            handle.join().unwrap();
-        }
-    }
-}
@@ -1,26 +1,53 @@
-use std::fs::File;
+use std::fs;
 use std::path::Path;
-use std::time::SystemTime;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
+use log::*;
+
+/// Checks a file for changes on a background thread
 pub struct FileWatcher {
-    f: File,
-    modified: SystemTime,
+    changed: Arc<AtomicBool>,
 }
 
 impl FileWatcher {
-    pub fn new(file: impl AsRef<Path>) -> anyhow::Result<Self> {
-        let f = File::open(file)?;
-        let modified = f.metadata()?.modified()?;
-        Ok(FileWatcher { f, modified })
+    const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+    /// Watch `file`, `on_change` is called on the watcher thread every time it changes.
+    ///
+    /// The path is checked rather than an open handle, so a file that is replaced (as git and
+    /// many editors do, by renaming a new file over it) is noticed too.
+    pub fn new(file: impl AsRef<Path>, on_change: impl Fn() + Send + 'static) -> anyhow::Result<Self> {
+        let path = file.as_ref().to_path_buf();
+        let mut modified = fs::metadata(&path)?.modified()?;
+        let changed = Arc::new(AtomicBool::new(false));
+        let watched = Arc::downgrade(&changed);
+        _ = std::thread::spawn(move || {
+            loop {
+                std::thread::sleep(Self::POLL_INTERVAL);
+                // Stop once the watcher is dropped
+                let Some(changed) = watched.upgrade() else { break };
+                // While a file is replaced, the path can briefly be missing
+                let new_modified = match fs::metadata(&path).and_then(|metadata| metadata.modified()) {
+                    Ok(new_modified) => new_modified,
+                    Err(err) => {
+                        debug!("Couldn't check the file for changes: {}", err);
+                        continue;
+                    },
+                };
+                if new_modified != modified {
+                    modified = new_modified;
+                    changed.store(true, Ordering::Relaxed);
+                    on_change();
+                }
+            }
+        });
+        Ok(FileWatcher { changed })
     }
 
+    /// Whether the file changed since the last call
     pub fn changed(&mut self) -> anyhow::Result<bool> {
-        let new_mod = self.f.metadata()?.modified()?;
-        if self.modified != new_mod {
-            self.modified = new_mod;
-            return Ok(true);
-        } else {
-            return Ok(false);
-        }
+        Ok(self.changed.swap(false, Ordering::Relaxed))
     }
 }
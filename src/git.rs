@@ -0,0 +1,154 @@
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+
+use log::*;
+use regex::Regex;
+
+#[derive(Debug, Clone)]
+enum GitError {
+    RunError(String)
+}
+
+impl std::error::Error for GitError {}
+impl std::fmt::Display for GitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GitError::RunError(msg) => f.write_fmt(format_args!("Couldn't run `git`: {}", msg)),
+        }
+    }
+}
+
+fn git<I, S>(dir: impl AsRef<Path>, args: I) -> anyhow::Result<Vec<u8>>
+where I: IntoIterator<Item = S>,
+      S: AsRef<OsStr>,
+{
+    let out = Command::new("git")
+        .arg("-C")
+        .arg(dir.as_ref())
+        .args(args)
+        .output()?;
+    if !out.status.success() {
+        return Err(GitError::RunError(String::from_utf8_lossy(&out.stderr).trim().to_string()).into());
+    }
+
+    return Ok(out.stdout);
+}
+
+/// Resolve `.` and `..` without touching the file system
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {},
+            Component::ParentDir => { normalized.pop(); },
+            component => normalized.push(component),
+        }
+    }
+    return normalized;
+}
+
+/// Files referenced by a stylesheet through external entities or XInclude
+fn stylesheet_includes(xml: &str) -> Vec<String> {
+    let include_regex = Regex::new(r#"SYSTEM\s+"([^"]+)"|SYSTEM\s+'([^']+)'|<xi:include[^>]*\shref\s*=\s*"([^"]+)""#).unwrap();
+    include_regex.captures_iter(xml)
+        .filter_map(|captures| captures.iter().skip(1).flatten().next().map(|m| m.as_str().to_string()))
+        .filter(|include| !include.contains("://"))
+        .collect()
+}
+
+/// Write the version of `stylesheet` at `revision`, together with the files it includes, to `out_dir`.
+///
+/// The directory structure relative to the repository root is kept, so relative includes keep
+/// working. Returns the path of the extracted stylesheet.
+pub fn checkout_stylesheet(stylesheet: impl AsRef<Path>, revision: &str, out_dir: impl AsRef<Path>) -> anyhow::Result<PathBuf> {
+    let stylesheet = stylesheet.as_ref().canonicalize()?;
+    let stylesheet_dir = stylesheet.parent().unwrap();
+    let toplevel = String::from_utf8(git(stylesheet_dir, ["rev-parse", "--show-toplevel"])?)?;
+    let toplevel = PathBuf::from(toplevel.trim()).canonicalize()?;
+    let stylesheet_rel = stylesheet.strip_prefix(&toplevel)?.to_path_buf();
+
+    let out_dir = out_dir.as_ref();
+    if out_dir.exists() {
+        fs::remove_dir_all(out_dir)?;
+    }
+
+    let mut visited = HashSet::new();
+    let mut queue = vec![stylesheet_rel.clone()];
+    while let Some(rel) = queue.pop() {
+        if !visited.insert(rel.clone()) { continue }
+
+        // git expects forward slashes, also on Windows
+        let object = format!("{}:{}", revision, rel.components().map(|c| c.as_os_str().to_string_lossy()).collect::<Vec<_>>().join("/"));
+        let out_path = out_dir.join(&rel);
+        fs::create_dir_all(out_path.parent().unwrap())?;
+
+        let content = match git(&toplevel, ["show", object.as_str()]) {
+            Ok(content) => content,
+            Err(err) if rel != stylesheet_rel => {
+                warn!("{} is not in {}, the working copy will be used: {}", rel.display(), revision, err);
+                match fs::read(toplevel.join(&rel)) {
+                    Ok(content) => content,
+                    Err(_) => continue,
+                }
+            },
+            Err(err) => return Err(err),
+        };
+        fs::write(&out_path, &content)?;
+
+        let rel_dir = rel.parent().unwrap_or(Path::new(""));
+        for include in stylesheet_includes(&String::from_utf8_lossy(&content)) {
+            let include = Path::new(&include);
+            if include.is_absolute() { continue }
+            queue.push(normalize(&rel_dir.join(include)));
+        }
+    }
+
+    info!("Extracted {} at {} to {}", stylesheet_rel.display(), revision, out_dir.display());
+    return Ok(out_dir.join(stylesheet_rel));
+}
+
+/// A stylesheet checked out at HEAD, which can be checked out again when HEAD moves
+#[derive(Debug)]
+pub struct HeadCheckout {
+    stylesheet: PathBuf,
+    out_dir: PathBuf,
+    path: PathBuf,
+}
+
+impl HeadCheckout {
+    /// Check out `stylesheet` at HEAD to `out_dir`, see `checkout_stylesheet`
+    pub fn new(stylesheet: impl AsRef<Path>, out_dir: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let stylesheet = stylesheet.as_ref().to_path_buf();
+        let out_dir = out_dir.as_ref().to_path_buf();
+        let path = checkout_stylesheet(&stylesheet, "HEAD", &out_dir)?;
+        Ok(Self { stylesheet, out_dir, path })
+    }
+
+    /// The checked out stylesheet
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// A file that is written whenever HEAD moves: the reflog of HEAD, or HEAD itself when the
+    /// repository keeps no reflog (which misses commits on the current branch)
+    pub fn head_file(&self) -> anyhow::Result<PathBuf> {
+        let dir = self.stylesheet.canonicalize()?.parent().unwrap().to_path_buf();
+        for git_path in ["logs/HEAD", "HEAD"] {
+            let path = String::from_utf8(git(&dir, ["rev-parse", "--git-path", git_path])?)?;
+            let path = dir.join(path.trim());
+            if path.exists() {
+                return Ok(path);
+            }
+        }
+        Err(anyhow::format_err!("No HEAD found for {}", self.stylesheet.display()))
+    }
+
+    /// Check out HEAD again, to the same path
+    pub fn update(&self) -> anyhow::Result<()> {
+        checkout_stylesheet(&self.stylesheet, "HEAD", &self.out_dir)?;
+        Ok(())
+    }
+}
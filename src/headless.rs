@@ -0,0 +1,269 @@
+use std::ffi::{CStr, CString};
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use log::*;
+use serde::Deserialize;
+
+use crate::app::config::Config;
+use crate::app::controls::Controls;
+use crate::{cairo::*, crop, crop_offset, map_renderer, metatile_job, metatile_of, tile_ranges, Box2d, CancelToken, MapRendererMemberExt as _, Point, ProjTransformMemberExt as _, RenderBackend, RenderJob, RenderPool, Stylesheet};
+
+/// Restores the controls of the last viewer session, so a headless render shows the same view
+pub(crate) fn load_controls(cachefile: impl AsRef<Path>) -> anyhow::Result<Controls> {
+    if !cachefile.as_ref().exists() {
+        return Ok(Controls::default());
+    }
+
+    let cachereader = BufReader::new(File::open(cachefile.as_ref())?);
+    let mut cache: serde_json::Value = serde_json::from_reader(cachereader)?;
+    let Some(controls) = cache.as_object_mut().and_then(|obj| obj.get_mut("controls")) else {
+        return Ok(Controls::default());
+    };
+    return Controls::from_json(controls.take());
+}
+
+/// Render `stylesheet` to a PNG file without opening a window, using the view of the last session
+pub fn render_to_png(
+    stylesheet: &Stylesheet,
+    base_path: impl AsRef<Path>,
+    cachefile: impl AsRef<Path>,
+    output: impl AsRef<Path>,
+    backend: RenderBackend,
+) -> anyhow::Result<()> {
+    let controls = load_controls(cachefile)?;
+    let w = controls.map_width;
+    let h = controls.map_height;
+    info!("Rendering {} ({}x{}, {:?}) to {}", stylesheet, w, h, backend, output.as_ref().display());
+
+    let surface = unsafe { cairo_image_surface_create(_cairo_format_CAIRO_FORMAT_ARGB32, w as i32, h as i32) };
+    let result = (|| -> anyhow::Result<()> {
+        let cr: *mut cairo_t = unsafe { cairo_create(surface) };
+        let cr_mapnik: *mut map_renderer::ffi::cairo_t = unsafe { std::mem::transmute(cr) };
+        let cr_mapnik = unsafe { map_renderer::ffi::make_cairo_shared(cr_mapnik) };
+
+        let mut map_renderer = stylesheet.load(w, h, cr_mapnik, base_path)?;
+        map_renderer.pin_mut().zoom_to_box(&controls.create_center_box(w, h));
+        map_renderer.pin_mut().set_backend(backend);
+        map_renderer.pin_mut().render()?;
+        drop(map_renderer); // releases the cairo context
+
+        return write_surface_png(surface, output);
+    })();
+    unsafe { cairo_surface_destroy(surface) };
+
+    return result;
+}
+
+/// A view in a views file: the controls as in the cache file, and where to write the PNG
+#[derive(Debug, Deserialize)]
+struct HeadlessView {
+    output: PathBuf,
+    #[serde(flatten)]
+    controls: serde_json::Value,
+}
+
+/// Render every view in `views_file` to its own PNG file, spread over `workers` threads
+pub fn render_views_to_png(
+    stylesheet: &Stylesheet,
+    base_path: impl AsRef<Path>,
+    views_file: impl AsRef<Path>,
+    workers: usize,
+    backend: RenderBackend,
+) -> anyhow::Result<()> {
+    let reader = BufReader::new(File::open(views_file.as_ref())?);
+    let views: Vec<HeadlessView> = serde_json::from_reader(reader)?;
+    let views = views.into_iter()
+        .map(|view| Ok((Controls::from_json(view.controls)?, view.output)))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    if views.is_empty() {
+        return Err(anyhow::format_err!("No views in {}", views_file.as_ref().display()));
+    }
+
+    let pool = RenderPool::new(workers.min(views.len()), stylesheet, base_path, backend)?;
+    info!("Rendering {} views of {} on {} workers with {:?}", views.len(), stylesheet, pool.workers(), backend);
+    let jobs = views.iter()
+        .map(|(controls, _)| RenderJob {
+            bbox: controls.create_center_box(controls.map_width, controls.map_height),
+            w: controls.map_width,
+            h: controls.map_height,
+        })
+        .collect();
+    let surfaces = pool.render(jobs, &Arc::new(CancelToken::default()))?;
+
+    for ((_, output), surface) in views.iter().zip(surfaces) {
+        let surface = surface.ok_or_else(|| anyhow::format_err!("The render of {} was cancelled", output.display()))?;
+        write_surface_png(surface.as_ptr(), output)?;
+        info!("Wrote {}", output.display());
+    }
+    return Ok(());
+}
+
+/// The tiles `--seed` renders ahead of time
+#[derive(Debug, Clone, PartialEq)]
+pub struct TileSeed {
+    /// The area to cover, in the input projection of the last session
+    pub bbox: Box2d<f64>,
+    /// The scale denominators to render the area at
+    pub scales: Vec<f64>,
+    /// Tiles are written to `<output>/<scale>/<x>/<y>.png`
+    pub output: PathBuf,
+}
+
+impl TileSeed {
+    /// Parse the arguments of `--seed`: the bounding box as `minx,miny,maxx,maxy` and the scale
+    /// denominators separated by commas
+    pub fn parse(bbox: &str, scales: &str, output: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let numbers = |list: &str| list.split(',')
+            .map(|number| number.trim().parse::<f64>())
+            .collect::<Result<Vec<_>, _>>();
+        let coordinates = numbers(bbox).map_err(|err| anyhow::format_err!("Invalid bounding box {}: {}", bbox, err))?;
+        let [startx, starty, endx, endy] = coordinates[..] else {
+            return Err(anyhow::format_err!("Invalid bounding box {}: expected minx,miny,maxx,maxy", bbox));
+        };
+        if !(startx < endx && starty < endy) {
+            return Err(anyhow::format_err!("Empty bounding box {}", bbox));
+        }
+        let scales = numbers(scales).map_err(|err| anyhow::format_err!("Invalid scales {}: {}", scales, err))?;
+        if let Some(scale) = scales.iter().find(|scale| !(**scale > 0.0 && scale.is_finite())) {
+            return Err(anyhow::format_err!("Invalid scale {}", scale));
+        }
+        return Ok(Self {
+            bbox: Box2d { startx, starty, endx, endy },
+            scales,
+            output: output.into(),
+        });
+    }
+}
+
+/// Render the tiles of `seed` on the grid of the viewer, with the tile settings of `configfile`
+/// and the projections of the last session.
+///
+/// The metatiles are spread over the workers of a render pool, a few at a time so the renders
+/// don't all have to be kept in memory.
+pub fn seed_tiles(
+    stylesheet: &Stylesheet,
+    base_path: impl AsRef<Path>,
+    cachefile: impl AsRef<Path>,
+    configfile: impl AsRef<Path>,
+    seed: &TileSeed,
+    workers: Option<usize>,
+    backend: Option<RenderBackend>,
+) -> anyhow::Result<()> {
+    let mut controls = load_controls(cachefile)?;
+    let config = Config::load(configfile)?.render;
+    let size = config.tile_size as i64;
+    let metatile = config.metatile.max(1) as i64;
+    let margin = config.tile_margin as i64;
+
+    // The corners in the output projection, which the grid is on
+    let transform = controls.proj_transform();
+    let bbox = seed.bbox;
+    let corners = [(bbox.startx, bbox.starty), (bbox.startx, bbox.endy), (bbox.endx, bbox.starty), (bbox.endx, bbox.endy)].into_iter()
+        .map(|(x, y)| -> anyhow::Result<Point<f64>> { Ok(transform.forward(&Point::new(x, y))?) })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let (minx, maxx) = corners.iter().fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), p| (min.min(p.x), max.max(p.x)));
+    let (miny, maxy) = corners.iter().fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), p| (min.min(p.y), max.max(p.y)));
+
+    let pool = RenderPool::new(workers.unwrap_or_else(|| config.worker_count()), stylesheet, base_path, backend.unwrap_or(config.backend))?;
+    info!("Seeding tiles of {} at {} scales on {} workers to {}", stylesheet, seed.scales.len(), pool.workers(), seed.output.display());
+    let cancel = Arc::new(CancelToken::default());
+    for &scale in &seed.scales {
+        controls.set_scale_denominator(scale);
+        let upp = controls.units_per_pixel_scale;
+        let left = (minx / upp).floor() as i64;
+        let top = (maxy / upp).ceil() as i64;
+        let w = ((maxx / upp).ceil() as i64 - left).max(1);
+        let h = (top - (miny / upp).floor() as i64).max(1);
+        let (columns, rows) = tile_ranges(left, top, w, h, size);
+        let (first_x, first_y) = metatile_of(*columns.start(), *rows.start(), metatile);
+        let (last_x, last_y) = metatile_of(*columns.end(), *rows.end(), metatile);
+        let metatiles: Vec<(i64, i64)> = (first_y..=last_y)
+            .flat_map(|my| (first_x..=last_x).map(move |mx| (mx, my)))
+            .collect();
+
+        let mut seeded = 0;
+        for batch in metatiles.chunks(pool.workers() * 4) {
+            let jobs = batch.iter()
+                .map(|&meta| metatile_job(meta, metatile, size, margin, upp))
+                .collect();
+            let surfaces = pool.render(jobs, &cancel)?;
+            for (&(mx, my), surface) in batch.iter().zip(surfaces) {
+                let surface = surface.ok_or_else(|| anyhow::format_err!("The render of metatile {},{} was cancelled", mx, my))?;
+                for ty in 0..metatile {
+                    for tx in 0..metatile {
+                        let (x, y) = (mx * metatile + tx, my * metatile + ty);
+                        if !columns.contains(&x) || !rows.contains(&y) {
+                            continue;
+                        }
+                        let (u, v) = crop_offset(tx, ty, metatile, size, margin);
+                        let tile = crop(&surface, u, v, config.tile_size);
+                        let dir = seed.output.join(scale.to_string()).join(x.to_string());
+                        fs::create_dir_all(&dir)?;
+                        write_surface_png(tile.as_ptr(), dir.join(format!("{}.png", y)))?;
+                        seeded += 1;
+                    }
+                }
+            }
+        }
+        info!("Seeded {} tiles at 1:{}", seeded, scale);
+    }
+    return Ok(());
+}
+
+/// Write a frame of the renderer thread (premultiplied BGRA, `w * 4` bytes per row) to a PNG file
+pub(crate) fn write_buffer_png(buffer: &[u8], w: u32, h: u32, output: impl AsRef<Path>) -> anyhow::Result<()> {
+    let stride = w as usize * 4;
+    if buffer.len() < stride * h as usize {
+        return Err(anyhow::format_err!("Buffer too small for a {}x{} image", w, h));
+    }
+    // The buffer holds the pixels cairo rendered, so the PNG is the same as one written by the
+    // renderer. cairo doesn't take ownership of the data, but wants it mutable.
+    let mut data: Vec<u8> = buffer[..stride * h as usize].chunks_exact(4)
+        .flat_map(|bgra| u32::from_le_bytes([bgra[0], bgra[1], bgra[2], bgra[3]]).to_ne_bytes())
+        .collect();
+    let surface = unsafe { cairo_image_surface_create_for_data(data.as_mut_ptr(), _cairo_format_CAIRO_FORMAT_ARGB32, w as i32, h as i32, stride as i32) };
+    let result = write_surface_png(surface, output);
+    unsafe { cairo_surface_destroy(surface) };
+
+    return result;
+}
+
+fn write_surface_png(surface: *mut cairo_surface_t, output: impl AsRef<Path>) -> anyhow::Result<()> {
+    unsafe { cairo_surface_flush(surface) };
+    let filename = CString::new(output.as_ref().as_os_str().as_encoded_bytes())?;
+    let status = unsafe { cairo_surface_write_to_png(surface, filename.as_ptr()) };
+    if status != _cairo_status_CAIRO_STATUS_SUCCESS {
+        let msg = unsafe { CStr::from_ptr(cairo_status_to_string(status)) };
+        return Err(anyhow::format_err!("Couldn't write {}: {}", output.as_ref().display(), msg.to_string_lossy()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_seed_arguments() {
+        let seed = TileSeed::parse("3.5, 50.5,6,51.5", "25000,50000", "tiles").unwrap();
+        assert_eq!(seed.bbox, Box2d { startx: 3.5, starty: 50.5, endx: 6.0, endy: 51.5 });
+        assert_eq!(seed.scales, vec![25000.0, 50000.0]);
+        assert_eq!(seed.output, PathBuf::from("tiles"));
+    }
+
+    #[test]
+    fn rejects_invalid_seed_arguments() {
+        assert!(TileSeed::parse("3.5,50.5,6", "25000", "tiles").is_err());
+        assert!(TileSeed::parse("3.5,50.5,6,51.5,1", "25000", "tiles").is_err());
+        assert!(TileSeed::parse("3.5,west,6,51.5", "25000", "tiles").is_err());
+        assert!(TileSeed::parse("6,50.5,3.5,51.5", "25000", "tiles").is_err());
+        assert!(TileSeed::parse("3.5,50.5,6,50.5", "25000", "tiles").is_err());
+        assert!(TileSeed::parse("3.5,50.5,6,51.5", "", "tiles").is_err());
+        assert!(TileSeed::parse("3.5,50.5,6,51.5", "25000,0", "tiles").is_err());
+        assert!(TileSeed::parse("3.5,50.5,6,51.5", "-25000", "tiles").is_err());
+        assert!(TileSeed::parse("3.5,50.5,6,51.5", "inf", "tiles").is_err());
+    }
+}
@@ -2,7 +2,15 @@ pub mod map_renderer;
 pub use map_renderer::*;
 mod screen_map_renderer;
 pub use screen_map_renderer::*;
+mod tile_cache;
+pub use tile_cache::*;
+mod render_pool;
+pub use render_pool::*;
 pub mod mapnik_config;
+mod stylesheet;
+pub use stylesheet::*;
+pub mod headless;
+pub mod git;
 
 pub mod ext;
 pub mod app;
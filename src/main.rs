@@ -7,7 +7,7 @@ use log4rs::config::Appender;
 use log4rs::encode::pattern::PatternEncoder;
 use log4rs::config::Logger;
 use map_explorer::ffi::ostream;
-use map_explorer::{app, mapnik_config, new_Pipe, new_PipeInputStream, new_PipeOutputStream, setup_mapnik, UniqueSendPtr};
+use map_explorer::{app, git, headless, default_workers, mapnik_config, new_Pipe, new_PipeInputStream, new_PipeOutputStream, read_stylesheet, setup_mapnik, spawn_stdin_reader, RenderBackend, Stylesheet, UniqueSendPtr};
 use regex::Regex;
 
 fn main() -> anyhow::Result<()> {
@@ -27,12 +27,53 @@ fn main() -> anyhow::Result<()> {
     // Parse args
     let mut args = std::env::args();
     let progname = args.next().unwrap(); // always present
-    let mapfile = args.next().unwrap_or("map.xml".to_string());
-    let basepath = args.next().unwrap_or(".".to_string());
-
-    if args.next().is_some() {
-        return Err(anyhow::format_err!("Invalid argument.\nUsage: {} [mapnik stylesheet path] [basepath]", progname));
+    let usage = || anyhow::format_err!("Invalid argument.\nUsage: {} [--render <output.png> | --render-views <views.json> | --seed <minx,miny,maxx,maxy> <scale,...> <output dir>] [--workers <n>] [--backend <cairo | agg>] [--compare <mapnik stylesheet path> | --compare-head] [mapnik stylesheet path | -] [basepath]", progname);
+
+    let mut render_output: Option<String> = None;
+    let mut render_views: Option<String> = None;
+    let mut seed: Option<headless::TileSeed> = None;
+    let mut workers: Option<usize> = None;
+    let mut backend: Option<RenderBackend> = None;
+    let mut compare_mapfile: Option<String> = None;
+    let mut compare_head = false;
+    let mut positional = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--render" => render_output = Some(args.next().ok_or_else(usage)?),
+            "--render-views" => render_views = Some(args.next().ok_or_else(usage)?),
+            "--seed" => {
+                let bbox = args.next().ok_or_else(usage)?;
+                let scales = args.next().ok_or_else(usage)?;
+                seed = Some(headless::TileSeed::parse(&bbox, &scales, args.next().ok_or_else(usage)?)?);
+            },
+            "--workers" => workers = Some(args.next().ok_or_else(usage)?.parse().map_err(|_| usage())?),
+            "--backend" => backend = Some(args.next().ok_or_else(usage)?.parse()?),
+            "--compare" => compare_mapfile = Some(args.next().ok_or_else(usage)?),
+            "--compare-head" => compare_head = true,
+            _ if arg.starts_with("--") => return Err(usage()),
+            _ => positional.push(arg),
+        }
+    }
+    let headless_modes = [render_output.is_some(), render_views.is_some(), seed.is_some()];
+    if positional.len() > 2 || headless_modes.into_iter().filter(|&mode| mode).count() > 1 {
+        return Err(usage());
     }
+    let mut positional = positional.into_iter();
+    let mapfile = positional.next().unwrap_or("map.xml".to_string());
+    let basepath = positional.next().unwrap_or(".".to_string());
+
+    // `-` reads the stylesheet from stdin. The viewer keeps listening for new
+    // versions, separated by a NUL byte. The first one has to be complete (NUL or EOF)
+    // before the window opens.
+    let (stylesheet, read_stdin_updates) = if mapfile == "-" {
+        let stdin = std::io::stdin();
+        let Some(xml) = read_stylesheet(&mut stdin.lock())? else {
+            return Err(anyhow::format_err!("No stylesheet received on stdin"));
+        };
+        (Stylesheet::Xml(xml), !headless_modes.contains(&true))
+    } else {
+        (Stylesheet::File(mapfile.into()), false)
+    };
 
     let projdirs = directories::ProjectDirs::from("be", "jonaseveraert", "MapExplorer").unwrap();
     let cache_dir = projdirs.cache_dir();
@@ -42,9 +83,19 @@ fn main() -> anyhow::Result<()> {
 
     let inifile = cache_dir.join("MapExplorer.ini");
     let cachefile = cache_dir.join("cache.json");
+    let configfile = projdirs.config_dir().join("config.json");
 
     info!("inifile: {}", inifile.display());
     info!("cachefile: {}", cachefile.display());
+    info!("configfile: {}", configfile.display());
+
+    let compare = match (compare_mapfile, compare_head, &stylesheet) {
+        (Some(_), true, _) => return Err(usage()),
+        (Some(mapfile), false, _) => Some(app::CompareWith::Stylesheet(Stylesheet::File(mapfile.into()))),
+        (None, true, Stylesheet::File(mapfile)) => Some(app::CompareWith::Head(git::HeadCheckout::new(mapfile, cache_dir.join("HEAD"))?)),
+        (None, true, Stylesheet::Xml(_)) => return Err(anyhow::format_err!("--compare-head requires a stylesheet file")),
+        (None, false, _) => None,
+    };
 
     // TODO: logging
     let mut pipe = new_Pipe()?;
@@ -84,13 +135,38 @@ fn main() -> anyhow::Result<()> {
 
     setup_mapnik(&mapnik_config::input_plugins_dir()?, &mapnik_config::fonts_dir()?)?;
 
-    let w = 800;
-    let h = 600;
-
-    let event_loop = winit::event_loop::EventLoop::new()?;
-    event_loop.set_control_flow(winit::event_loop::ControlFlow::Poll);
-    let mut app = app::MapExplorer::new(w, h, mapfile, basepath, inifile, cachefile)?;
-    event_loop.run_app(&mut app)?;
+    if let Some(output) = render_output {
+        headless::render_to_png(&stylesheet, &basepath, &cachefile, &output, backend.unwrap_or_default())?;
+    } else if let Some(views) = render_views {
+        headless::render_views_to_png(&stylesheet, &basepath, &views, workers.unwrap_or_else(default_workers), backend.unwrap_or_default())?;
+    } else if let Some(seed) = seed {
+        headless::seed_tiles(&stylesheet, &basepath, &cachefile, &configfile, &seed, workers, backend)?;
+    } else {
+        let w = 800;
+        let h = 600;
+
+        let event_loop = winit::event_loop::EventLoop::<app::AppEvent>::with_user_event().build()?;
+        // Redraws are requested when something changes
+        event_loop.set_control_flow(winit::event_loop::ControlFlow::Wait);
+        let proxy = event_loop.create_proxy();
+        let stylesheet_updates = read_stdin_updates.then(|| {
+            let proxy = proxy.clone();
+            spawn_stdin_reader(move || { _ = proxy.send_event(app::AppEvent::StylesheetChanged); })
+        });
+        let options = app::MapExplorerOptions {
+            map_def: stylesheet,
+            map_def_updates: stylesheet_updates,
+            compare,
+            basepath: basepath.into(),
+            inifile,
+            cachefile,
+            configfile,
+            backend,
+            workers,
+        };
+        let mut app = app::MapExplorer::new(w, h, options, proxy)?;
+        event_loop.run_app(&mut app)?;
+    }
 
     map_explorer::ffi::restore_clog();
     unsafe { map_explorer::ffi::close_pipe(pipe.pin_mut_unchecked())? };
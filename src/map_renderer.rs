@@ -1,10 +1,19 @@
 use std::path::Path;
 use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use cxx::memory::{SharedPtrTarget, UniquePtrTarget};
 use cxx::{let_cxx_string, UniquePtr, SharedPtr};
+use serde::{Deserialize, Serialize};
 
 #[cxx::bridge]
 pub mod ffi {
+    extern "Rust" {
+        type CancelToken;
+
+        fn is_cancelled(self: &CancelToken) -> bool;
+    }
+
     unsafe extern "C++" {
         include!("MapRenderer.hpp");
         include!("glue.hpp");
@@ -27,7 +36,14 @@ pub mod ffi {
 
         fn resize(self: Pin<&mut MapRenderer>, w: u32, h: u32);
 
+        #[cxx_name = "update_styles"]
+        fn update_cxx_styles(self: Pin<&mut MapRenderer>, styles_def: Pin<&CxxString>, base_path: Pin<&CxxString>) -> Result<()>;
+
         fn render(self: Pin<&mut MapRenderer>) -> Result<()>;
+        /// Render layer by layer, stopping when `cancel` is cancelled. Returns whether the render completed.
+        fn render_cancellable(self: Pin<&mut MapRenderer>, cancel: &CancelToken) -> Result<bool>;
+        /// Render at 1/`divisor` of the size and scale it up, for a quick first look
+        fn render_preview(self: Pin<&mut MapRenderer>, cancel: &CancelToken, divisor: u32) -> Result<bool>;
 
         // #[cxx_name = "move"]
         // fn move_map(self: Pin<&mut MapRenderer>, x: f64, y: f64);
@@ -35,6 +51,8 @@ pub mod ffi {
         fn zoom(self: Pin<&mut MapRenderer>, startx: f64, starty: f64, endx: f64, endy: f64);
 
         fn set_cairo(self: Pin<&mut MapRenderer>, cr: SharedPtr<cairo_t>);
+        /// Render with AGG into an image that is painted on the cairo context
+        fn set_agg(self: Pin<&mut MapRenderer>, agg: bool);
 
         #[cxx_name = "zoom_to_box"]
         fn zoom_to_cxx_box(self: Pin<&mut MapRenderer>, bbox: Pin<&box2d_double>);
@@ -50,6 +68,9 @@ pub mod ffi {
         type point_double;
         fn new_point_double(x: f64, y: f64) -> SharedPtr<point_double>;
 
+        fn point_get_x(p: &point_double) -> f64;
+        fn point_get_y(p: &point_double) -> f64;
+
         #[namespace = "mapnik"]
         #[cxx_name = "projection"]
         type Projection;
@@ -57,8 +78,17 @@ pub mod ffi {
         fn new_projection(str: Pin<&CxxString>) -> Result<SharedPtr<Projection>>;
 
         fn projection_definition(proj: SharedPtr<Projection>) -> UniquePtr<CxxString>;
+        fn projection_is_geographic(proj: &Projection) -> bool;
         // TODO: definition
 
+        #[namespace = "mapnik"]
+        #[cxx_name = "proj_transform"]
+        type ProjTransform;
+
+        fn new_proj_transform(projsrc: &Projection, projdst: &Projection) -> Result<SharedPtr<ProjTransform>>;
+        fn proj_transform_forward(trans: &ProjTransform, p: &point_double) -> Result<SharedPtr<point_double>>;
+        fn proj_transform_backward(trans: &ProjTransform, p: &point_double) -> Result<SharedPtr<point_double>>;
+
         fn make_center_box(center: &point_double, projsrc: &Projection, projdst: &Projection, projected_units_per_pixel: f64, screen_w: u32, screen_h: u32) -> SharedPtr<box2d_double>;
 
         // Logging
@@ -109,6 +139,8 @@ pub trait MapRendererExt {
 
 pub trait MapRendererMemberExt {
     fn zoom_to_box(self, bbox: &Box2d<f64>);
+    fn set_backend(self, backend: RenderBackend);
+    fn update_styles(self, styles_def: &str, base_path: impl AsRef<Path>) -> cxx::core::result::Result<(), cxx::Exception>;
 }
 
 impl MapRendererExt for MapRenderer {
@@ -131,6 +163,59 @@ impl<'a> MapRendererMemberExt for Pin<&'a mut MapRenderer> {
         let pin = unsafe { bbox.pin_mut_unchecked() };
         MapRenderer::zoom_to_cxx_box(self, pin.as_ref());
     }
+
+    fn set_backend(self, backend: RenderBackend) {
+        MapRenderer::set_agg(self, backend == RenderBackend::Agg);
+    }
+
+    fn update_styles(self, styles_def: &str, base_path: impl AsRef<Path>) -> cxx::core::result::Result<(), cxx::Exception> {
+        let_cxx_string!(styles_def = styles_def);
+        let_cxx_string!(base_path = base_path.as_ref().as_os_str().as_encoded_bytes());
+        MapRenderer::update_cxx_styles(self, styles_def.as_ref(), base_path.as_ref())
+    }
+}
+
+/// The Mapnik renderer that draws the map
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RenderBackend {
+    /// Vector graphics through cairo
+    #[default]
+    Cairo,
+    /// AGG, which most tile servers render with. Antialiasing, labels and some symbolizers differ from cairo.
+    Agg,
+}
+
+impl FromStr for RenderBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cairo" => Ok(Self::Cairo),
+            "agg" => Ok(Self::Agg),
+            _ => Err(anyhow::format_err!("Unknown render backend {:?}, expected cairo or agg", s)),
+        }
+    }
+}
+
+/// Aborts a render from another thread, checked by the renderer between layers
+#[derive(Debug, Default)]
+pub struct CancelToken {
+    cancelled: AtomicBool,
+}
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn reset(&self) {
+        self.cancelled.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -195,6 +280,7 @@ pub fn setup_mapnik(datasources_dir: &str, fonts_dir: &str) -> cxx::core::result
     _setup_mapnik(datasources_dir.as_ref(), fonts_dir.as_ref())
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Point<T: Copy + Clone> {
     pub x: T,
     pub y: T
@@ -226,6 +312,13 @@ impl CXXPointCapable for f64 {
     }
 }
 
+impl Point<f64> {
+    fn from_cxx(p: SharedPtr<point_double>) -> Point<f64> {
+        let p = p.as_ref().unwrap();
+        return Point::new(point_get_x(p), point_get_y(p));
+    }
+}
+
 pub trait ProjectionExt {
     fn new(str: &str) -> cxx::core::result::Result<SharedPtr<Projection>, cxx::Exception>;
 }
@@ -239,12 +332,53 @@ impl ProjectionExt for Projection {
 
 pub trait ProjectionMemberExt {
     fn definition(&self) -> String;
+    /// Whether the units of the projection are degrees
+    fn is_geographic(&self) -> bool;
 }
 
 impl ProjectionMemberExt for SharedPtr<Projection> {
     fn definition(&self) -> String {
         projection_definition(self.clone()).to_string()
     }
+
+    fn is_geographic(&self) -> bool {
+        projection_is_geographic(self.as_ref().unwrap())
+    }
+}
+
+pub trait ProjTransformExt {
+    fn new(projsrc: &SharedPtr<Projection>, projdst: &SharedPtr<Projection>) -> cxx::core::result::Result<SharedPtr<ProjTransform>, cxx::Exception>;
+}
+
+impl ProjTransformExt for ProjTransform {
+    fn new(projsrc: &SharedPtr<Projection>, projdst: &SharedPtr<Projection>) -> cxx::core::result::Result<SharedPtr<ProjTransform>, cxx::Exception> {
+        return new_proj_transform(projsrc.as_ref().unwrap(), projdst.as_ref().unwrap());
+    }
+}
+
+pub trait ProjTransformMemberExt {
+    /// Transform a point from the source to the destination projection
+    fn forward(&self, p: &Point<f64>) -> cxx::core::result::Result<Point<f64>, cxx::Exception>;
+    /// Transform a point from the destination to the source projection
+    fn backward(&self, p: &Point<f64>) -> cxx::core::result::Result<Point<f64>, cxx::Exception>;
+}
+
+impl ProjTransformMemberExt for SharedPtr<ProjTransform> {
+    fn forward(&self, p: &Point<f64>) -> cxx::core::result::Result<Point<f64>, cxx::Exception> {
+        let transformed = proj_transform_forward(self.as_ref().unwrap(), p.as_cxx().as_ref().unwrap())?;
+        return Ok(Point::from_cxx(transformed));
+    }
+
+    fn backward(&self, p: &Point<f64>) -> cxx::core::result::Result<Point<f64>, cxx::Exception> {
+        let transformed = proj_transform_backward(self.as_ref().unwrap(), p.as_cxx().as_ref().unwrap())?;
+        return Ok(Point::from_cxx(transformed));
+    }
+}
+
+impl std::fmt::Debug for ProjTransform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ProjTransform { .. }")
+    }
 }
 
 impl std::fmt::Debug for Projection {
@@ -266,4 +400,4 @@ pub struct SharedSendPtr<T: SharedPtrTarget> {
 
 unsafe impl<T: SharedPtrTarget> Send for SharedSendPtr<T> {}
 
-pub use ffi::{MapRenderer, Projection, new_Pipe, new_PipeInputStream, new_PipeOutputStream, Pipe, PipeInputStream, PipeOutputStream, set_logging};
+pub use ffi::{MapRenderer, Projection, ProjTransform, new_Pipe, new_PipeInputStream, new_PipeOutputStream, Pipe, PipeInputStream, PipeOutputStream, set_logging};
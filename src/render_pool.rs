@@ -0,0 +1,256 @@
+use std::path::Path;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::JoinHandle;
+
+use cxx::{SharedPtr, UniquePtr};
+use log::*;
+
+use crate::{cairo::*, map_renderer, Box2d, CancelToken, MapRenderer, MapRendererMemberExt as _, MapRendererTask, RenderBackend, Stylesheet};
+
+/// The number of workers when none is configured: one per core, up to 8
+pub fn default_workers() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get()).min(8)
+}
+
+/// A part of the map to render at a size
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RenderJob {
+    pub bbox: Box2d<f64>,
+    pub w: u32,
+    pub h: u32,
+}
+
+/// A cairo image surface to render on, destroyed when dropped
+pub struct RenderedSurface {
+    surface: *mut cairo_surface_t,
+}
+
+// One thread draws on a surface at a time, clones of finished tiles are only read and cairo
+// counts the references atomically
+unsafe impl Send for RenderedSurface {}
+
+impl RenderedSurface {
+    /// A new transparent ARGB32 surface
+    pub fn new(w: u32, h: u32) -> Self {
+        Self {
+            surface: unsafe { cairo_image_surface_create(_cairo_format_CAIRO_FORMAT_ARGB32, w as i32, h as i32) },
+        }
+    }
+
+    pub fn as_ptr(&self) -> *mut cairo_surface_t {
+        self.surface
+    }
+
+    /// Width and height in pixels
+    pub fn size(&self) -> (u32, u32) {
+        unsafe { (cairo_image_surface_get_width(self.surface) as u32, cairo_image_surface_get_height(self.surface) as u32) }
+    }
+
+    /// A cairo context drawing on the surface, as the renderer takes it
+    pub fn context(&self) -> SharedPtr<map_renderer::ffi::cairo_t> {
+        let cr: *mut cairo_t = unsafe { cairo_create(self.surface) };
+        let cr_mapnik: *mut map_renderer::ffi::cairo_t = unsafe { std::mem::transmute(cr) };
+        unsafe { map_renderer::ffi::make_cairo_shared(cr_mapnik) }
+    }
+
+    /// Bytes per row of [`Self::data`], cairo may pad the rows
+    pub fn stride(&self) -> usize {
+        unsafe { cairo_image_surface_get_stride(self.surface) as usize }
+    }
+
+    /// The pixels as cairo stores them: premultiplied ARGB in native endian words, [`Self::stride`] bytes per row
+    pub fn data(&self) -> &[u8] {
+        unsafe {
+            cairo_surface_flush(self.surface);
+            let len = cairo_image_surface_get_stride(self.surface) as usize * cairo_image_surface_get_height(self.surface) as usize;
+            std::slice::from_raw_parts(cairo_image_surface_get_data(self.surface), len)
+        }
+    }
+
+    /// Copy the pixels to `pixels` as premultiplied BGRA bytes, without padding between the rows.
+    ///
+    /// The values are cairo's, so nothing is lost until the frame is composited or written to a PNG.
+    pub fn copy_pixels(&self, pixels: &mut Vec<u8>) {
+        let (w, h) = self.size();
+        pixels.clear();
+        pixels.reserve(w as usize * h as usize * 4);
+        for row in self.data().chunks_exact(self.stride()) {
+            for argb in row[..w as usize * 4].chunks_exact(4) {
+                // BGRA is a little endian ARGB word
+                pixels.extend_from_slice(&u32::from_ne_bytes([argb[0], argb[1], argb[2], argb[3]]).to_le_bytes());
+            }
+        }
+    }
+}
+
+/// Another reference to the same surface, cairo destroys it when the last one is dropped
+impl Clone for RenderedSurface {
+    fn clone(&self) -> Self {
+        Self {
+            surface: unsafe { cairo_surface_reference(self.surface) },
+        }
+    }
+}
+
+impl Drop for RenderedSurface {
+    fn drop(&mut self) {
+        unsafe { cairo_surface_destroy(self.surface); }
+    }
+}
+
+/// Render `job` on a new surface, `None` when cancelled.
+///
+/// Leaves `map_renderer` at the size and extent of the job.
+pub(crate) fn render_surface(map_renderer: &mut UniquePtr<MapRenderer>, job: &RenderJob, cancel: &CancelToken) -> anyhow::Result<Option<RenderedSurface>> {
+    let surface = RenderedSurface::new(job.w, job.h);
+    map_renderer.pin_mut().resize(job.w, job.h);
+    map_renderer.pin_mut().zoom_to_box(&job.bbox);
+    map_renderer.pin_mut().set_cairo(surface.context());
+    let completed = map_renderer.pin_mut().render_cancellable(cancel)?;
+    return Ok(completed.then_some(surface));
+}
+
+/// A job and where to send its surface
+struct QueuedJob {
+    index: usize,
+    job: RenderJob,
+    cancel: Arc<CancelToken>,
+    results: mpsc::Sender<(usize, anyhow::Result<Option<RenderedSurface>>)>,
+}
+
+struct Worker {
+    tasks: mpsc::Sender<MapRendererTask>,
+    join: JoinHandle<()>,
+}
+
+/// Worker threads that each render with their own copy of a stylesheet.
+///
+/// Jobs are handed to whichever worker is free.
+pub struct RenderPool {
+    jobs: Option<mpsc::Sender<QueuedJob>>,
+    workers: Vec<Worker>,
+}
+
+impl RenderPool {
+    /// Start `workers` threads and load `stylesheet` on each of them, rendering with `backend`
+    pub fn new(workers: usize, stylesheet: &Stylesheet, base_path: impl AsRef<Path>, backend: RenderBackend) -> anyhow::Result<Self> {
+        let workers = workers.max(1);
+        info!("Starting {} render workers", workers);
+        let (job_sender, job_receiver) = mpsc::channel::<QueuedJob>();
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+        let (loaded_sender, loaded_receiver) = mpsc::channel::<anyhow::Result<()>>();
+
+        let workers = (0..workers).map(|i| {
+            let (task_sender, task_receiver) = mpsc::channel::<MapRendererTask>();
+            let job_receiver = job_receiver.clone();
+            let loaded_sender = loaded_sender.clone();
+            let stylesheet = stylesheet.clone();
+            let base_path = base_path.as_ref().to_path_buf();
+            std::thread::Builder::new()
+                .name(format!("render worker {}", i))
+                .spawn(move || {
+                    let mut map_renderer = match load(&stylesheet, &base_path, backend) {
+                        Ok(map_renderer) => {
+                            _ = loaded_sender.send(Ok(()));
+                            map_renderer
+                        },
+                        Err(err) => {
+                            _ = loaded_sender.send(Err(err));
+                            return;
+                        },
+                    };
+                    drop(loaded_sender);
+
+                    loop {
+                        // Hold the lock only while waiting, so the other workers can render
+                        let queued = job_receiver.lock().map_err(|err| anyhow::format_err!("{}", err))
+                            .and_then(|receiver| Ok(receiver.recv()?));
+                        let Ok(queued) = queued else { break }; // the pool was dropped
+
+                        // Tasks sent before the job apply to it
+                        while let Ok(task) = task_receiver.try_recv() {
+                            if let Err(err) = task(&mut map_renderer) {
+                                error!("{}", err);
+                            }
+                        }
+
+                        let result = if queued.cancel.is_cancelled() {
+                            Ok(None)
+                        } else {
+                            render_surface(&mut map_renderer, &queued.job, &queued.cancel)
+                        };
+                        _ = queued.results.send((queued.index, result));
+                    }
+                })
+                .map(|join| Worker { tasks: task_sender, join })
+        }).collect::<Result<Vec<_>, _>>()?;
+        drop(loaded_sender);
+
+        let pool = Self { jobs: Some(job_sender), workers };
+        for loaded in loaded_receiver.iter() {
+            loaded?;
+        }
+        return Ok(pool);
+    }
+
+    pub fn workers(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Run `task` on every worker before its next job
+    pub fn run(&self, task: MapRendererTask) -> anyhow::Result<()> {
+        for worker in &self.workers {
+            worker.tasks.send(task.clone()).map_err(|err| anyhow::format_err!("{}", err))?;
+        }
+        Ok(())
+    }
+
+    /// Render `jobs` on the workers, waiting until all of them are done.
+    ///
+    /// The surfaces are in the order of `jobs`, `None` for the jobs that were cancelled.
+    pub fn render(&self, jobs: Vec<RenderJob>, cancel: &Arc<CancelToken>) -> anyhow::Result<Vec<Option<RenderedSurface>>> {
+        let Some(sender) = &self.jobs else { return Err(anyhow::format_err!("The render pool was stopped")) };
+        let (results_sender, results_receiver) = mpsc::channel();
+        let len = jobs.len();
+        for (index, job) in jobs.into_iter().enumerate() {
+            sender.send(QueuedJob { index, job, cancel: cancel.clone(), results: results_sender.clone() })
+                .map_err(|err| anyhow::format_err!("{}", err))?;
+        }
+        drop(results_sender);
+
+        let mut surfaces: Vec<Option<RenderedSurface>> = std::iter::repeat_with(|| None).take(len).collect();
+        let mut error = None;
+        // Every job reports back, even after an error, so no worker still renders for this call
+        for (index, result) in results_receiver.iter() {
+            match result {
+                Ok(surface) => surfaces[index] = surface,
+                Err(err) => error = error.or(Some(err)),
+            }
+        }
+        match error {
+            Some(err) => Err(err),
+            None => Ok(surfaces),
+        }
+    }
+}
+
+impl Drop for RenderPool {
+    fn drop(&mut self) {
+        // Workers stop when the job queue is closed
+        self.jobs = None;
+        for worker in self.workers.drain(..) {
+            if worker.join.join().is_err() {
+                error!("A render worker panicked");
+            }
+        }
+    }
+}
+
+/// Load the stylesheet on a worker, with a placeholder surface until the first job
+fn load(stylesheet: &Stylesheet, base_path: &Path, backend: RenderBackend) -> anyhow::Result<UniquePtr<MapRenderer>> {
+    // The context keeps the surface alive
+    let cr = RenderedSurface::new(1, 1).context();
+    let mut map_renderer = stylesheet.load(1, 1, cr, base_path)?;
+    map_renderer.pin_mut().set_backend(backend);
+    return Ok(map_renderer);
+}
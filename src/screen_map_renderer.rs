@@ -1,23 +1,13 @@
-use std::collections::VecDeque;
 use std::path::Path;
-use std::sync::{mpsc, Arc, Mutex, MutexGuard, PoisonError};
-use std::time::Duration;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use cxx::{SharedPtr, UniquePtr};
+use cxx::UniquePtr;
+use log::*;
 
-use crate::{cairo::*, map_renderer, MapRenderer, MapRendererExt};
-
-struct CairoSurfacesCloser<const BUFFER_SIZE: usize> {
-    surfaces: Arc<[*mut cairo_surface_t; BUFFER_SIZE]>
-}
-
-impl<const BUFFER_SIZE: usize> Drop for CairoSurfacesCloser<BUFFER_SIZE> {
-    fn drop(&mut self) {
-        for i in 0..BUFFER_SIZE {
-            unsafe { cairo_surface_destroy(self.surfaces[i]); }
-        }
-    }
-}
+use crate::{CancelToken, MapRenderer, RenderPool, RenderedSurface, Stylesheet, Tiling};
 
 pub struct MapRendererAndUserData<UserData: 'static + Clone> {
     map_renderer: UniquePtr<MapRenderer>,
@@ -42,238 +32,391 @@ impl<UserData: 'static + Clone> MapRendererAndUserData<UserData> {
     }
 }
 
+/// Work to run on the renderer thread, e.g. updating the styles of the map.
+///
+/// Also runs on every worker of the render pool, each with its own map.
+pub type MapRendererTask = Arc<dyn Fn(&mut UniquePtr<MapRenderer>) -> anyhow::Result<()> + Send + Sync>;
+
+/// How a frame was rendered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameQuality {
+    /// Rendered at a reduced resolution and scaled up, the full quality frame follows
+    Preview,
+    Full,
+}
+
 // -DMAPNIK_THREADSAGE
 unsafe impl<UserData: 'static + Clone> Send for MapRendererAndUserData<UserData> {}
 
+/// What the renderer thread waits for
+enum Message<UserData> {
+    UserData(UserData),
+    /// Render at a new width and height from now on, with user data for that size
+    Resize(u32, u32, UserData),
+    Task(MapRendererTask),
+    Quit,
+}
+
 /// Threaded map renderer
-pub struct ScreenMapRenderer<const BUFFER_SIZE: usize, UserData: 'static + Clone + Send> {
-    surfaces: Arc<[*mut cairo_surface_t; BUFFER_SIZE]>,
-    contexts: [SharedPtr<crate::map_renderer::ffi::cairo_t>; BUFFER_SIZE],
-    used: [bool; BUFFER_SIZE],
-    idx: usize,
-    reuse_queue: Arc<Mutex<Vec<usize>>>,
-    buffers: Arc<Mutex<ScreenMapRendererBuffers<BUFFER_SIZE, UserData>>>,
+pub struct ScreenMapRenderer<UserData: 'static + Clone + Send> {
+    /// Frames are rendered here, then copied to a buffer for the UI
+    surface: RenderedSurface,
+    mailbox: Arc<Mailbox<ScreenMapRendererBuffer<UserData>>>,
     map_renderer_and_user_data: Arc<Mutex<MapRendererAndUserData<UserData>>>,
     on_receive_userdata: Box<dyn Fn(&mut UniquePtr<MapRenderer>, &UserData) -> ()>,
-    // map_renderer: Arc<Mutex<UniquePtr<MapRenderer>>>,
-    _surfaces_closer: Arc<CairoSurfacesCloser<BUFFER_SIZE>>,
-    // user_data: Arc<Mutex<UserData>>,
     rerender_only_when_new_user_data: bool,
+    /// Render a preview at 1/`preview_divisor` of the size first, when full renders are slow
+    preview_divisor: Option<u32>,
+    /// Compose full quality frames from cached tiles instead of rendering them at once
+    tiling: Option<Tiling<UserData>>,
+    /// Renders the tiles in parallel, instead of this thread one by one
+    render_pool: Option<RenderPool>,
+    /// Called on the renderer thread after every frame
+    on_frame: Option<Box<dyn Fn() + Send>>,
 }
 
-unsafe impl<const BUFFER_SIZE: usize, UserData: 'static + Clone + Send> Send for ScreenMapRenderer<BUFFER_SIZE, UserData> {}
+unsafe impl<UserData: 'static + Clone + Send> Send for ScreenMapRenderer<UserData> {}
 
-impl<const BUFFER_SIZE: usize, UserData: 'static + Clone + Send> ScreenMapRenderer<BUFFER_SIZE, UserData> {
-    pub fn new_from_file(
+impl<UserData: 'static + Clone + Send> ScreenMapRenderer<UserData> {
+    pub fn new(
         w: u32, h: u32,
-        map_def_file: impl AsRef<Path>,
+        stylesheet: &Stylesheet,
         base_path: impl AsRef<Path>,
         user_data: UserData,
         on_receive_userdata: Box<dyn Fn(&mut UniquePtr<MapRenderer>, &UserData) -> ()>,
-    ) -> anyhow::Result<(Self, Arc<Mutex<ScreenMapRendererBuffers<BUFFER_SIZE, UserData>>>)> {
-        let surfaces: Arc<[*mut cairo_surface_t; BUFFER_SIZE]> = Arc::new(std::array::from_fn(|_| {
-            unsafe { cairo_image_surface_create(_cairo_format_CAIRO_FORMAT_ARGB32, w as i32, h as i32) }
-        }));
-        let surfaces_closer = Arc::new(CairoSurfacesCloser {
-            surfaces: surfaces.clone(),
-        });
-
-        let contexts: [SharedPtr<crate::map_renderer::ffi::cairo_t>; BUFFER_SIZE] = std::array::from_fn(|i| {
-            let surf = surfaces[i];
-            let cr: *mut cairo_t = unsafe { cairo_create(surf) };
-            let cr_mapnik: *mut crate::map_renderer::ffi::cairo_t = unsafe { std::mem::transmute(cr) };
-            unsafe { map_renderer::ffi::make_cairo_shared(cr_mapnik) }
-        });
-
-        let reuse_queue = Arc::new(Mutex::new(Vec::new()));
-        let buffers = Arc::new(Mutex::new(ScreenMapRendererBuffers::new(
-            BUFFER_SIZE,
-            unsafe { (cairo_image_surface_get_width(surfaces[0]) as usize) * (cairo_image_surface_get_height(surfaces[0]) as usize) * (cairo_image_surface_get_stride(surfaces[0]) as usize) },
-            reuse_queue.clone(),
-            surfaces_closer.clone()
-        )));
-        let ctx1 = contexts[0].clone();
+    ) -> anyhow::Result<(Self, ScreenMapRendererBuffers<UserData>)> {
+        let surface = RenderedSurface::new(w, h);
+        let mailbox = Arc::new(Mailbox::new());
+        let map_renderer = stylesheet.load(w, h, surface.context(), base_path)?;
         return Ok((Self {
-            surfaces,
-            contexts,
-            used: [false; BUFFER_SIZE],
-            idx: 0,
-            reuse_queue,
-            buffers: buffers.clone(),
+            surface,
+            mailbox: mailbox.clone(),
             map_renderer_and_user_data: Arc::new(Mutex::new(MapRendererAndUserData {
-                map_renderer: MapRenderer::new_from_file(w, h, map_def_file, ctx1, base_path)?,
+                map_renderer,
                 user_data,
             })),
             on_receive_userdata,
-            _surfaces_closer: surfaces_closer,
             rerender_only_when_new_user_data: true,
-        }, buffers));
+            preview_divisor: None,
+            tiling: None,
+            render_pool: None,
+            on_frame: None,
+        }, ScreenMapRendererBuffers { mailbox }));
     }
 
-    #[deprecated]
-    pub fn map_renderer_and_user_data(&self) -> Arc<Mutex<MapRendererAndUserData<UserData>>> {
-        self.map_renderer_and_user_data.clone()
+    /// Compose full quality frames from tiles, only rendering the tiles that aren't cached
+    pub fn set_tiling(&mut self, tiling: Option<Tiling<UserData>>) {
+        self.tiling = tiling;
     }
 
-    fn try_reuse_buffers(&mut self) -> Result<(), PoisonError<MutexGuard<'_, Vec<usize>>>> {
-        match self.reuse_queue.try_lock() {
-            Ok(mut queue) => {
-                for buffer_idx in queue.iter() {
-                    self.used[*buffer_idx] = false;
-                }
-                queue.clear();
-                return Ok(());
-            },
-            Err(err) => match err {
-                std::sync::TryLockError::Poisoned(poison_error) => return Err(poison_error),
-                std::sync::TryLockError::WouldBlock => return Ok(()),
-            },
+    /// Render the tiles on the workers of `pool`, which have to be loaded with the same stylesheet
+    pub fn set_render_pool(&mut self, pool: Option<RenderPool>) {
+        self.render_pool = pool;
+    }
+
+    /// Call `on_frame` every time a frame is ready, e.g. to wake up the UI
+    pub fn set_on_frame(&mut self, on_frame: Option<Box<dyn Fn() + Send>>) {
+        self.on_frame = on_frame;
+    }
+
+    /// Render a quick preview at 1/`divisor` of the width and height before every full render.
+    ///
+    /// Only used while full renders take longer than [`Self::PREVIEW_THRESHOLD`].
+    pub fn set_preview_divisor(&mut self, divisor: Option<u32>) {
+        self.preview_divisor = divisor.filter(|divisor| *divisor > 1);
+    }
+
+    const PREVIEW_THRESHOLD: Duration = Duration::from_millis(100);
+
+    /// The quality to render a new view at first
+    fn first_quality(&self, last_full_render: Option<Duration>) -> FrameQuality {
+        let slow = last_full_render.is_none_or(|duration| duration > Self::PREVIEW_THRESHOLD);
+        if self.preview_divisor.is_some() && slow {
+            FrameQuality::Preview
+        } else {
+            FrameQuality::Full
         }
     }
 
-    pub fn start(self) -> (ScreenMapRendererJoinHandle, std::sync::mpsc::SyncSender<UserData>) {
-        let (quit_sender, quit_receiver) = std::sync::mpsc::channel::<()>();
-        let (ud_sender, ud_receiver) = std::sync::mpsc::sync_channel::<UserData>(100);
+    #[deprecated]
+    pub fn map_renderer_and_user_data(&self) -> Arc<Mutex<MapRendererAndUserData<UserData>>> {
+        self.map_renderer_and_user_data.clone()
+    }
+
+    pub fn start(self) -> (ScreenMapRendererJoinHandle<UserData>, UserDataSender<UserData>) {
+        let (sender, receiver) = mpsc::channel::<Message<UserData>>();
+        let cancel = Arc::new(CancelToken::default());
+        let queued_user_data = Arc::new(AtomicUsize::new(0));
+        let cancelled_renders = Arc::new(AtomicUsize::new(0));
+        let ud_sender = UserDataSender {
+            sender: sender.clone(),
+            cancel: cancel.clone(),
+            queued: queued_user_data.clone(),
+        };
+        let thread_cancelled_renders = cancelled_renders.clone();
         return (ScreenMapRendererJoinHandle {
             join: std::thread::spawn(move || {
                 let mut ren = self;
-                let mut ud_received = false;
+                // The quality of the next frame, if there is anything to render
+                let mut pending: Option<FrameQuality> = None;
+                let mut last_full_render: Option<Duration> = None;
+                let mut generation = 0;
+                // Buffers the UI is done with
+                let (recycle, recycled) = mpsc::channel::<Vec<u8>>();
                 loop {
-                    match quit_receiver.try_recv() {
-                        Ok(()) => break,
-                        Err(err) => match err {
-                            mpsc::TryRecvError::Empty => {},
-                            mpsc::TryRecvError::Disconnected => return Err(mpsc::TryRecvError::Disconnected.into()),
-                        },
+                    if !ren.rerender_only_when_new_user_data {
+                        pending.get_or_insert(FrameQuality::Full);
                     }
 
-                    loop {
-                        match ud_receiver.try_recv() {
-                            Ok(ud) => {
-                                ud_received = true;
+                    // Sleep until there is something to do, then take everything that arrived
+                    let first = match pending {
+                        Some(_) => None,
+                        None => match receiver.recv() {
+                            Ok(message) => Some(message),
+                            Err(mpsc::RecvError) => return Ok(()), // every sender is gone
+                        },
+                    };
+                    // Only user data that arrives from now on cancels the render
+                    cancel.reset();
+                    for message in first.into_iter().chain(std::iter::from_fn(|| receiver.try_recv().ok())) {
+                        match message {
+                            Message::Quit => return Ok(()),
+                            Message::UserData(ud) => {
+                                queued_user_data.fetch_sub(1, Ordering::Relaxed);
+                                pending = Some(ren.first_quality(last_full_render));
                                 let mut guard = ren.map_renderer_and_user_data.lock().map_err(|err| anyhow::format_err!("{}", err))?;
                                 guard.set_user_data(ud.clone());
                                 ren.on_receive_userdata.as_ref()(&mut guard.map_renderer, &ud)
                             },
-                            Err(err) => match err{
-                                mpsc::TryRecvError::Empty => break,
-                                mpsc::TryRecvError::Disconnected => return Err(mpsc::TryRecvError::Disconnected.into()),
+                            Message::Resize(w, h, ud) => {
+                                queued_user_data.fetch_sub(1, Ordering::Relaxed);
+                                pending = Some(ren.first_quality(last_full_render));
+                                if ren.surface.size() != (w, h) {
+                                    debug!("Resizing to {}x{}", w, h);
+                                    ren.surface = RenderedSurface::new(w, h);
+                                }
+                                let mut guard = ren.map_renderer_and_user_data.lock().map_err(|err| anyhow::format_err!("{}", err))?;
+                                guard.map_renderer.pin_mut().resize(w, h);
+                                guard.set_user_data(ud.clone());
+                                ren.on_receive_userdata.as_ref()(&mut guard.map_renderer, &ud)
+                            },
+                            Message::Task(task) => {
+                                let mut guard = ren.map_renderer_and_user_data.lock().map_err(|err| anyhow::format_err!("{}", err))?;
+                                if let Err(err) = task(&mut guard.map_renderer) {
+                                    error!("{}", err);
+                                }
+                                if let Some(pool) = &ren.render_pool
+                                    && let Err(err) = pool.run(task)
+                                {
+                                    error!("{}", err);
+                                }
+                                generation += 1;
+                                pending = Some(ren.first_quality(last_full_render)); // rerender with the result of the task
                             },
                         }
                     }
 
-                    ren.try_reuse_buffers().map_err(|err| anyhow::format_err!("{}", err))?;
-
-                    if ren.used[ren.idx] {
-                        ren.idx = (ren.idx + 1) % BUFFER_SIZE;
+                    let Some(quality) = pending else { continue };
+                    let surf = ren.surface.as_ptr();
+
+                    let mut guard = ren.map_renderer_and_user_data.lock().map_err(|err| anyhow::format_err!("{}", err))?;
+                    let map_renderer_and_ud = &mut *guard;
+                    let map_renderer = &mut map_renderer_and_ud.map_renderer;
+                    map_renderer.pin_mut().set_cairo(ren.surface.context());
+                    let start = Instant::now();
+                    let user_data = match (quality, ren.preview_divisor, &mut ren.tiling) {
+                        (FrameQuality::Preview, Some(divisor), _) => map_renderer.pin_mut().render_preview(&cancel, divisor)?
+                            .then(|| map_renderer_and_ud.user_data.clone()),
+                        (FrameQuality::Full, _, Some(tiling)) => tiling.render(map_renderer, ren.render_pool.as_ref(), &map_renderer_and_ud.user_data, generation, surf, &cancel)?,
+                        _ => map_renderer.pin_mut().render_cancellable(&cancel)?
+                            .then(|| map_renderer_and_ud.user_data.clone()),
+                    };
+                    drop(guard);
+                    let Some(user_data) = user_data else {
+                        // `pending` stays set, so the newest view is rendered next. The cancel of
+                        // user data that was already taken can land after the reset, then the
+                        // render wasn't stale and is just done again.
+                        if queued_user_data.load(Ordering::Relaxed) > 0 {
+                            let cancelled = thread_cancelled_renders.fetch_add(1, Ordering::Relaxed) + 1;
+                            debug!("Cancelled a stale render ({} so far)", cancelled);
+                        }
                         continue;
+                    };
+
+                    let mut pixels = recycled.try_recv().unwrap_or_default();
+                    ren.surface.copy_pixels(&mut pixels);
+                    let (width, height) = ren.surface.size();
+                    // A frame the UI didn't take in time is dropped, which recycles its buffer
+                    ren.mailbox.put(ScreenMapRendererBuffer {
+                        pixels,
+                        width,
+                        height,
+                        generation,
+                        quality,
+                        user_data,
+                        recycle: recycle.clone(),
+                    });
+                    if let Some(on_frame) = &ren.on_frame {
+                        on_frame();
                     }
 
-                    if (ren.rerender_only_when_new_user_data && ud_received) || !(ren.rerender_only_when_new_user_data) {
-                        let surf = &ren.surfaces[ren.idx];
-                        let cr = &ren.contexts[ren.idx];
-
-                        let mut map_renderer_and_ud = ren.map_renderer_and_user_data.lock().map_err(|err| anyhow::format_err!("{}", err))?;
-                        let map_renderer = &mut map_renderer_and_ud.map_renderer;
-                        map_renderer.pin_mut().set_cairo(cr.clone());
-                        map_renderer.pin_mut().render()?;
-
-                        let mut buffers = ren.buffers.lock()
-                            .map_err(|err| anyhow::format_err!("{}", err))?;
-                        buffers.add_buffer((
-                            unsafe { cairo_image_surface_get_data(*surf) },
-                            ren.idx,
-                            map_renderer_and_ud.user_data.clone()
-                            // ren.user_data.lock().map_err(|err| anyhow::format_err!("{}", err))?.clone()
-                        ));
-                        ren.used[ren.idx] = true;
-
-                        ren.idx = (ren.idx + 1) % BUFFER_SIZE;
-                        ud_received = false;
-                    }
-
-                    std::thread::sleep(Duration::from_millis(10));
+                    pending = match quality {
+                        FrameQuality::Preview => Some(FrameQuality::Full),
+                        FrameQuality::Full => {
+                            last_full_render = Some(start.elapsed());
+                            None
+                        },
+                    };
                 }
-                return Ok(());
             }),
-            signal: quit_sender
+            sender,
+            cancelled_renders,
         }, ud_sender);
     }
 }
 
-pub struct ScreenMapRendererJoinHandle {
+/// Sends new user data to the renderer thread, cancelling the render in progress
+pub struct UserDataSender<UserData> {
+    sender: mpsc::Sender<Message<UserData>>,
+    cancel: Arc<CancelToken>,
+    /// User data sent that the renderer thread hasn't taken yet
+    queued: Arc<AtomicUsize>,
+}
+
+impl<UserData> UserDataSender<UserData> {
+    pub fn send(&self, user_data: UserData) -> anyhow::Result<()> {
+        self.send_message(Message::UserData(user_data))
+    }
+
+    /// Render `user_data` at `w`×`h` from now on, cancelling the render in progress.
+    ///
+    /// Frames rendered before the resize can still arrive, [`ScreenMapRendererBuffer::size`] tells them apart.
+    pub fn resize(&self, w: u32, h: u32, user_data: UserData) -> anyhow::Result<()> {
+        self.send_message(Message::Resize(w, h, user_data))
+    }
+
+    fn send_message(&self, message: Message<UserData>) -> anyhow::Result<()> {
+        // Counted before it can be taken, so the count never drops below zero
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        if self.sender.send(message).is_err() {
+            self.queued.fetch_sub(1, Ordering::Relaxed);
+            return Err(anyhow::format_err!("The renderer thread stopped"));
+        }
+        self.cancel.cancel();
+        Ok(())
+    }
+}
+
+pub struct ScreenMapRendererJoinHandle<UserData> {
     join: std::thread::JoinHandle<anyhow::Result<()>>,
-    signal: mpsc::Sender<()>,
+    sender: mpsc::Sender<Message<UserData>>,
+    cancelled_renders: Arc<AtomicUsize>,
 }
 
-impl ScreenMapRendererJoinHandle {
+impl<UserData> ScreenMapRendererJoinHandle<UserData> {
+    /// Run `task` on the renderer thread before the next render.
+    ///
+    /// Every task increases the generation of the buffers rendered afterwards.
+    pub fn run(&self, task: MapRendererTask) -> anyhow::Result<()> {
+        self.sender.send(Message::Task(task)).map_err(|_| anyhow::format_err!("The renderer thread stopped"))
+    }
+
+    /// The number of renders that were aborted because newer user data arrived
+    pub fn cancelled_renders(&self) -> usize {
+        self.cancelled_renders.load(Ordering::Relaxed)
+    }
+
     pub fn join(self) -> anyhow::Result<()> {
-        self.signal.send(())?;
+        // The thread may already have stopped with an error, which `join` returns
+        _ = self.sender.send(Message::Quit);
         self.join.join().map_err(|err| anyhow::format_err!("{:?}", err))?
     }
 }
 
-pub struct ScreenMapRendererBuffers<const BUFFER_SIZE: usize, UserData: 'static + Clone> {
-    buffers: VecDeque<(*const u8, usize, UserData)>,
-    len: usize,
-    reuse_queue: Arc<Mutex<Vec<usize>>>,
-    surfaces_closer: Arc<CairoSurfacesCloser<BUFFER_SIZE>>
+/// Holds at most one value, replaced by newer ones until it's taken. Never blocks.
+struct Mailbox<T> {
+    slot: AtomicPtr<T>,
 }
 
-impl<const BUFFER_SIZE: usize, UserData: 'static + Clone> ScreenMapRendererBuffers<BUFFER_SIZE, UserData> {
-    fn new(cap: usize, len: usize, reuse_queue: Arc<Mutex<Vec<usize>>>, surfaces_closer: Arc<CairoSurfacesCloser<BUFFER_SIZE>>) -> Self {
-        Self {
-            buffers: VecDeque::with_capacity(cap),
-            len: len,
-            reuse_queue,
-            surfaces_closer
-        }
+// The value is moved between threads, never shared
+unsafe impl<T: Send> Send for Mailbox<T> {}
+unsafe impl<T: Send> Sync for Mailbox<T> {}
+
+impl<T> Mailbox<T> {
+    fn new() -> Self {
+        Self { slot: AtomicPtr::new(ptr::null_mut()) }
     }
 
-    fn add_buffer(&mut self, buffer: (*const u8, usize, UserData)) {
-        self.buffers.push_back(buffer);
+    /// Put `value` in the mailbox, returns the value it replaced if that wasn't taken
+    fn put(&self, value: T) -> Option<T> {
+        let previous = self.slot.swap(Box::into_raw(Box::new(value)), Ordering::AcqRel);
+        // The swap made this the only owner of `previous`
+        (!previous.is_null()).then(|| *unsafe { Box::from_raw(previous) })
     }
 
-    /// Try to get a buffer
-    pub fn get_buffer<'a>(&'a mut self) -> Option<ScreenMapRendererBuffer<BUFFER_SIZE, UserData>> {
-        if let Some(buffer) = self.buffers.pop_front() {
-            return Some(ScreenMapRendererBuffer {
-                buffer: buffer.0,
-                buffer_len: self.len,
-                index: buffer.1,
-                reuse_queue: self.reuse_queue.clone(),
-                _cairo_surfaces_closer: self.surfaces_closer.clone(),
-                user_data: buffer.2
-            });
-        } else {
-            return None;
-        }
+    fn take(&self) -> Option<T> {
+        let value = self.slot.swap(ptr::null_mut(), Ordering::AcqRel);
+        (!value.is_null()).then(|| *unsafe { Box::from_raw(value) })
+    }
+}
+
+impl<T> Drop for Mailbox<T> {
+    fn drop(&mut self) {
+        self.take();
+    }
+}
+
+/// Receives the frames of a renderer thread
+pub struct ScreenMapRendererBuffers<UserData: 'static + Clone> {
+    mailbox: Arc<Mailbox<ScreenMapRendererBuffer<UserData>>>,
+}
+
+impl<UserData: 'static + Clone> ScreenMapRendererBuffers<UserData> {
+    /// The newest frame, if one was rendered since the last call. Older frames are skipped.
+    pub fn get_buffer(&self) -> Option<ScreenMapRendererBuffer<UserData>> {
+        self.mailbox.take()
     }
 }
 
-pub struct ScreenMapRendererBuffer<const BUFFER_SIZE: usize, UserData: 'static + Clone> {
-    buffer: *const u8,
-    buffer_len: usize,
-    index: usize,
-    reuse_queue: Arc<Mutex<Vec<usize>>>,
-    /// Prevents the need for lifetimes in this struct
-    _cairo_surfaces_closer: Arc<CairoSurfacesCloser<BUFFER_SIZE>>,
-    user_data: UserData
+/// A rendered frame, its buffer goes back to the renderer thread when dropped
+pub struct ScreenMapRendererBuffer<UserData: 'static + Clone> {
+    pixels: Vec<u8>,
+    width: u32,
+    height: u32,
+    generation: usize,
+    quality: FrameQuality,
+    user_data: UserData,
+    recycle: mpsc::Sender<Vec<u8>>,
 }
 
-impl<const BUFFER_SIZE: usize, UserData: 'static + Clone> ScreenMapRendererBuffer<BUFFER_SIZE, UserData> {
+impl<UserData: 'static + Clone> ScreenMapRendererBuffer<UserData> {
+    /// The pixels as sRGB encoded, premultiplied BGRA bytes, `4 * width` bytes per row
     pub fn buffer(&self) -> &[u8] {
-        // self.buffer
-        unsafe { std::slice::from_raw_parts(self.buffer, self.buffer_len) }
+        &self.pixels
+    }
+
+    /// Width and height of the frame in pixels
+    pub fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
     }
 
     pub fn user_data(&self) -> &UserData {
         &self.user_data
     }
+
+    /// The number of tasks that ran on the renderer thread before this buffer was rendered
+    pub fn generation(&self) -> usize {
+        self.generation
+    }
+
+    pub fn quality(&self) -> FrameQuality {
+        self.quality
+    }
 }
 
-impl<const BUFFER_SIZE: usize, UserData: 'static + Clone> Drop for ScreenMapRendererBuffer<BUFFER_SIZE, UserData> {
+impl<UserData: 'static + Clone> Drop for ScreenMapRendererBuffer<UserData> {
     fn drop(&mut self) {
-        self.reuse_queue.lock().unwrap().push(self.index);
+        // The renderer thread may be gone, then the buffer is freed
+        _ = self.recycle.send(std::mem::take(&mut self.pixels));
     }
 }
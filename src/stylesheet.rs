@@ -0,0 +1,210 @@
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+
+use cxx::{SharedPtr, UniquePtr};
+use log::*;
+
+use crate::map_renderer::ffi::cairo_t;
+use crate::{MapRenderer, MapRendererExt};
+
+/// A Mapnik stylesheet, either a file on disk or an XML document held in memory
+#[derive(Debug, Clone)]
+pub enum Stylesheet {
+    File(PathBuf),
+    Xml(String),
+}
+
+impl Stylesheet {
+    pub fn load(
+        &self,
+        w: u32, h: u32,
+        cairo: SharedPtr<cairo_t>,
+        base_path: impl AsRef<Path>
+    ) -> cxx::core::result::Result<UniquePtr<MapRenderer>, cxx::Exception> {
+        match self {
+            Stylesheet::File(path) => MapRenderer::new_from_file(w, h, path, cairo, base_path),
+            Stylesheet::Xml(xml) => MapRenderer::new(w, h, xml, cairo, base_path),
+        }
+    }
+
+    /// The XML of the stylesheet, without resolving entities or includes
+    pub fn read_xml(&self) -> anyhow::Result<String> {
+        match self {
+            Stylesheet::File(path) => Ok(std::fs::read_to_string(path)?),
+            Stylesheet::Xml(xml) => Ok(xml.clone()),
+        }
+    }
+}
+
+/// Remove all `<name>` elements, including nested ones, from an XML document.
+///
+/// This is not a full XML parser: comments and CDATA sections are kept as they
+/// are, and a `>` inside an attribute value ends the tag.
+pub(crate) fn remove_elements(xml: &str, name: &str) -> String {
+    let mut out = String::with_capacity(xml.len());
+    let mut depth = 0usize;
+    let mut rest = xml;
+    while let Some(start) = rest.find('<') {
+        let (text, tag_and_rest) = rest.split_at(start);
+        if depth == 0 { out.push_str(text); }
+
+        let terminator = if tag_and_rest.starts_with("<!--") {
+            "-->"
+        } else if tag_and_rest.starts_with("<![CDATA[") {
+            "]]>"
+        } else {
+            ">"
+        };
+        let end = tag_and_rest.find(terminator).map(|i| i + terminator.len()).unwrap_or(tag_and_rest.len());
+        let tag = &tag_and_rest[..end];
+        rest = &tag_and_rest[end..];
+
+        let (closing, tag_name) = match tag.strip_prefix("</") {
+            Some(tag_name) => (true, tag_name),
+            None => (false, &tag[1..]),
+        };
+        let matches = terminator == ">"
+            && tag_name.strip_prefix(name).is_some_and(|after| after.starts_with(|c: char| c.is_whitespace() || c == '>' || c == '/'));
+        if matches {
+            if closing {
+                depth = depth.saturating_sub(1);
+            } else if !tag.ends_with("/>") {
+                depth += 1;
+            }
+        } else if depth == 0 {
+            out.push_str(tag);
+        }
+    }
+    if depth == 0 { out.push_str(rest); }
+    return out;
+}
+
+/// Whether the difference between two versions of a stylesheet is limited to its `<Style>` elements
+pub(crate) fn only_styles_changed(old_xml: &str, new_xml: &str) -> bool {
+    remove_elements(old_xml, "Style") == remove_elements(new_xml, "Style")
+}
+
+/// The stylesheet without its layers, so it can be loaded without opening any datasources
+pub(crate) fn styles_only(xml: &str) -> String {
+    remove_elements(xml, "Layer")
+}
+
+impl std::fmt::Display for Stylesheet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Stylesheet::File(path) => f.write_fmt(format_args!("{}", path.display())),
+            Stylesheet::Xml(_) => f.write_str("<in-memory stylesheet>"),
+        }
+    }
+}
+
+/// Reads the next stylesheet from `input`.
+///
+/// Stylesheets are separated by a NUL byte, so that a generator can keep
+/// writing new versions to the same stream. Returns `None` at end of input.
+pub fn read_stylesheet(input: &mut impl BufRead) -> anyhow::Result<Option<String>> {
+    let mut buf = Vec::new();
+    let n = input.read_until(b'\0', &mut buf)?;
+    if n == 0 {
+        return Ok(None);
+    }
+    if buf.last() == Some(&b'\0') {
+        buf.pop();
+    }
+    return Ok(Some(String::from_utf8(buf)?));
+}
+
+/// Keeps reading stylesheets from stdin on a background thread
+pub fn spawn_stdin_reader(on_update: impl Fn() + Send + 'static) -> mpsc::Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+    _ = std::thread::spawn(move || {
+        let stdin = std::io::stdin();
+        let mut input = stdin.lock();
+        loop {
+            match read_stylesheet(&mut input) {
+                Ok(Some(xml)) => {
+                    if xml.trim().is_empty() { continue }
+                    if sender.send(xml).is_err() { break }
+                    on_update();
+                },
+                Ok(None) => {
+                    info!("stdin closed, no more stylesheets will be read");
+                    break;
+                },
+                Err(err) => {
+                    error!("Couldn't read stylesheet from stdin: {}", err);
+                    break;
+                },
+            }
+        }
+    });
+    return receiver;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn removes_nested_elements() {
+        let xml = r#"<Map><Style name="a"><Rule><Style>x</Style></Rule></Style><Layer name="l"/></Map>"#;
+        assert_eq!(remove_elements(xml, "Style"), r#"<Map><Layer name="l"/></Map>"#);
+    }
+
+    #[test]
+    fn removes_self_closing_elements() {
+        let xml = "<Map><Style name=\"a\"/><Style/>\n<Layer/></Map>";
+        assert_eq!(remove_elements(xml, "Style"), "<Map>\n<Layer/></Map>");
+    }
+
+    #[test]
+    fn keeps_elements_with_a_longer_name() {
+        let xml = "<Map><Layer><StyleName>roads</StyleName></Layer></Map>";
+        assert_eq!(remove_elements(xml, "Style"), xml);
+    }
+
+    #[test]
+    fn keeps_comments() {
+        let xml = r#"<Map><!-- <Style name="old"> --><Layer/></Map>"#;
+        assert_eq!(remove_elements(xml, "Style"), xml);
+
+        // A comment inside a removed element doesn't close it
+        let xml = r#"<Map><Style name="a"><!-- </Style> --><Rule/></Style><Layer/></Map>"#;
+        assert_eq!(remove_elements(xml, "Style"), "<Map><Layer/></Map>");
+    }
+
+    #[test]
+    fn keeps_cdata() {
+        let xml = r#"<Map><Style name="a"><Rule><Filter><![CDATA[[x] > 1]]></Filter></Rule></Style><Parameter name="p"><![CDATA[<Style>]]></Parameter></Map>"#;
+        assert_eq!(remove_elements(xml, "Style"), r#"<Map><Parameter name="p"><![CDATA[<Style>]]></Parameter></Map>"#);
+    }
+
+    #[test]
+    fn detects_style_only_changes() {
+        let old = r#"<Map><Style name="roads"><Rule><LineSymbolizer stroke="red"/></Rule></Style><Layer name="roads"><StyleName>roads</StyleName></Layer></Map>"#;
+        let restyled = r#"<Map><Style name="roads"><Rule><LineSymbolizer stroke="blue"/></Rule></Style><Layer name="roads"><StyleName>roads</StyleName></Layer></Map>"#;
+        let relayered = r#"<Map><Style name="roads"><Rule><LineSymbolizer stroke="red"/></Rule></Style><Layer name="roads"><StyleName>other</StyleName></Layer></Map>"#;
+        assert!(only_styles_changed(old, restyled));
+        assert!(!only_styles_changed(old, relayered));
+        assert_eq!(styles_only(old), r#"<Map><Style name="roads"><Rule><LineSymbolizer stroke="red"/></Rule></Style></Map>"#);
+    }
+
+    #[test]
+    fn reads_nul_separated_stylesheets() {
+        let mut input = std::io::Cursor::new(b"<Map/>\0<Map>\n</Map>\0\0<Map srs=\"x\"/>".to_vec());
+        assert_eq!(read_stylesheet(&mut input).unwrap().as_deref(), Some("<Map/>"));
+        assert_eq!(read_stylesheet(&mut input).unwrap().as_deref(), Some("<Map>\n</Map>"));
+        // An empty document between two NULs, which the stdin reader skips
+        assert_eq!(read_stylesheet(&mut input).unwrap().as_deref(), Some(""));
+        // The last one ends at EOF
+        assert_eq!(read_stylesheet(&mut input).unwrap().as_deref(), Some("<Map srs=\"x\"/>"));
+        assert_eq!(read_stylesheet(&mut input).unwrap(), None);
+    }
+
+    #[test]
+    fn reads_nothing_from_empty_input() {
+        assert_eq!(read_stylesheet(&mut std::io::Cursor::new(Vec::new())).unwrap(), None);
+        assert!(read_stylesheet(&mut std::io::Cursor::new(vec![0xff, 0])).is_err());
+    }
+}
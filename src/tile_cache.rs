@@ -0,0 +1,432 @@
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+
+use cxx::UniquePtr;
+use log::*;
+
+use crate::{cairo::*, render_surface, Box2d, CancelToken, MapRenderer, MapRendererMemberExt as _, Point, RenderJob, RenderPool, RenderedSurface};
+
+/// A tile on the grid of a scale
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TileKey {
+    /// Generation of the styles the tile was rendered with
+    pub generation: usize,
+    /// Output projection
+    pub srs: String,
+    /// Bits of the units per pixel, tiles are only reused at exactly the same scale
+    pub scale: u64,
+    pub x: i64,
+    pub y: i64,
+}
+
+/// A cached tile
+struct Tile<T> {
+    tile: T,
+    last_used: u64,
+}
+
+/// Least recently used tiles are dropped when the cache is full.
+///
+/// `get` hands out clones, for surfaces another reference, so a tile that is dropped from the
+/// cache stays alive until the frame it's part of is composed.
+pub struct TileCache<T> {
+    tiles: HashMap<TileKey, Tile<T>>,
+    capacity: usize,
+    clock: u64,
+}
+
+impl<T: Clone> TileCache<T> {
+    pub fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        Self {
+            tiles: HashMap::with_capacity(capacity),
+            capacity,
+            clock: 0,
+        }
+    }
+
+    fn contains(&self, key: &TileKey) -> bool {
+        self.tiles.contains_key(key)
+    }
+
+    fn get(&mut self, key: &TileKey) -> Option<T> {
+        self.clock += 1;
+        let tile = self.tiles.get_mut(key)?;
+        tile.last_used = self.clock;
+        Some(tile.tile.clone())
+    }
+
+    /// Add a tile, dropping the least recently used ones to stay within the capacity
+    fn insert(&mut self, key: TileKey, tile: T) {
+        self.clock += 1;
+        while self.tiles.len() >= self.capacity && !self.tiles.contains_key(&key) {
+            let oldest = self.tiles.iter()
+                .min_by_key(|(_, tile)| tile.last_used)
+                .map(|(key, _)| key.clone());
+            let Some(oldest) = oldest else { break };
+            self.tiles.remove(&oldest);
+        }
+        self.tiles.insert(key, Tile { tile, last_used: self.clock });
+    }
+
+    pub fn len(&self) -> usize {
+        self.tiles.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tiles.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.tiles.clear();
+    }
+}
+
+/// The part of the map a view shows, in the output projection
+#[derive(Debug, Clone, PartialEq)]
+pub struct TiledView {
+    pub center: Point<f64>,
+    pub units_per_pixel: f64,
+    pub srs: String,
+}
+
+/// The view of the user data
+pub type TiledViewFn<UserData> = Box<dyn Fn(&UserData) -> anyhow::Result<TiledView>>;
+/// The user data with the center moved to a point in the output projection
+pub type WithCenterFn<UserData> = Box<dyn Fn(&UserData, Point<f64>) -> anyhow::Result<UserData>>;
+
+/// Renders views from fixed-size tiles aligned to a grid in the output projection, so panning
+/// only renders the newly exposed tiles.
+///
+/// Tiles are rendered in metatiles of `metatile` by `metatile` tiles with a margin of `margin`
+/// pixels, which is cut off. Like on a tile server, labels and symbols near the edge of a tile
+/// are placed knowing what's beyond it, so they aren't cut off or drawn twice.
+pub struct Tiling<UserData> {
+    tile_size: u32,
+    /// Tiles per side of a metatile
+    metatile: u32,
+    /// Pixels rendered beyond each edge of a metatile
+    margin: u32,
+    cache: TileCache<RenderedSurface>,
+    /// Generation of the cached tiles
+    generation: usize,
+    view: TiledViewFn<UserData>,
+    with_center: WithCenterFn<UserData>,
+}
+
+impl<UserData> Tiling<UserData> {
+    pub fn new(
+        tile_size: u32,
+        metatile: u32,
+        margin: u32,
+        cache_capacity: usize,
+        view: TiledViewFn<UserData>,
+        with_center: WithCenterFn<UserData>,
+    ) -> Self {
+        let metatile = metatile.max(1);
+        Self {
+            tile_size: tile_size.max(1),
+            metatile,
+            margin,
+            // The tiles of a metatile are rendered together, they should fit
+            cache: TileCache::new(cache_capacity.max((metatile * metatile) as usize)),
+            generation: 0,
+            view,
+            with_center,
+        }
+    }
+
+    /// Render the view of `user_data` into `surface`, rendering only the tiles that aren't cached.
+    ///
+    /// The tiles are rendered on the workers of `pool`, or one by one with `map_renderer`.
+    ///
+    /// The center is moved onto the pixel grid of the tiles, the returned user data is the view
+    /// that was rendered. `None` when `cancel` stopped the render, the tiles rendered until then
+    /// are kept.
+    pub(crate) fn render(
+        &mut self,
+        map_renderer: &mut UniquePtr<MapRenderer>,
+        pool: Option<&RenderPool>,
+        user_data: &UserData,
+        generation: usize,
+        surface: *mut cairo_surface_t,
+        cancel: &Arc<CancelToken>,
+    ) -> anyhow::Result<Option<UserData>> {
+        let (w, h) = unsafe { (cairo_image_surface_get_width(surface) as i64, cairo_image_surface_get_height(surface) as i64) };
+        let view = (self.view)(user_data)?;
+        let upp = view.units_per_pixel;
+        let size = self.tile_size as i64;
+        if generation != self.generation {
+            // Tiles with the old styles won't be used again
+            self.cache.clear();
+            self.generation = generation;
+        }
+
+        let (left, top) = frame_origin(&view, w, h);
+        let (columns, rows) = tile_ranges(left, top, w, h, size);
+
+        let tiles = self.render_tiles(map_renderer, pool, &view, generation, (columns, rows), cancel);
+
+        // Leave the renderer as it was, showing the whole frame
+        map_renderer.pin_mut().resize(w as u32, h as u32);
+        map_renderer.pin_mut().zoom_to_box(&Box2d {
+            startx: left as f64 * upp,
+            starty: (top - h) as f64 * upp,
+            endx: (left + w) as f64 * upp,
+            endy: top as f64 * upp,
+        });
+
+        let Some(tiles) = tiles? else { return Ok(None) };
+        trace!("Composing {} tiles, {} cached", tiles.len(), self.cache.len());
+        unsafe {
+            let cr = cairo_create(surface);
+            cairo_set_operator(cr, _cairo_operator_CAIRO_OPERATOR_SOURCE);
+            for (x, y, tile) in &tiles {
+                let (u, v) = ((x * size - left) as f64, (top - (y + 1) * size) as f64);
+                cairo_set_source_surface(cr, tile.as_ptr(), u, v);
+                cairo_rectangle(cr, u, v, size as f64, size as f64);
+                cairo_fill(cr);
+            }
+            cairo_destroy(cr);
+            cairo_surface_flush(surface);
+        }
+
+        let center = Point::new((left as f64 + w as f64 / 2.0) * upp, (top as f64 - h as f64 / 2.0) * upp);
+        return Ok(Some((self.with_center)(user_data, center)?));
+    }
+
+    /// The surfaces of the tiles in `columns` and `rows`, rendering the metatiles of the ones
+    /// that aren't cached
+    fn render_tiles(
+        &mut self,
+        map_renderer: &mut UniquePtr<MapRenderer>,
+        pool: Option<&RenderPool>,
+        view: &TiledView,
+        generation: usize,
+        (columns, rows): (RangeInclusive<i64>, RangeInclusive<i64>),
+        cancel: &Arc<CancelToken>,
+    ) -> anyhow::Result<Option<Vec<(i64, i64, RenderedSurface)>>> {
+        let size = self.tile_size as i64;
+        let metatile = self.metatile as i64;
+        let margin = self.margin as i64;
+        let upp = view.units_per_pixel;
+        let key = |x: i64, y: i64| TileKey {
+            generation,
+            srs: view.srs.clone(),
+            scale: upp.to_bits(),
+            x, y,
+        };
+        let mut tiles = Vec::new();
+        let mut missing = Vec::new();
+        for y in rows.clone().rev() {
+            for x in columns.clone() {
+                match self.cache.get(&key(x, y)) {
+                    Some(tile) => tiles.push((x, y, tile)),
+                    None => {
+                        let meta = metatile_of(x, y, metatile);
+                        if !missing.contains(&meta) {
+                            missing.push(meta);
+                        }
+                    },
+                }
+            }
+        }
+        let jobs: Vec<RenderJob> = missing.iter()
+            .map(|&meta| metatile_job(meta, metatile, size, margin, upp))
+            .collect();
+
+        let rendered = match pool {
+            Some(pool) => pool.render(jobs.clone(), cancel)?,
+            None => {
+                let mut rendered = Vec::with_capacity(jobs.len());
+                for job in &jobs {
+                    if cancel.is_cancelled() {
+                        break;
+                    }
+                    let Some(surface) = render_surface(map_renderer, job, cancel)? else { break };
+                    rendered.push(Some(surface));
+                }
+                rendered
+            },
+        };
+
+        // Tiles that were rendered are kept, even when the frame was cancelled
+        let complete = rendered.len() == jobs.len() && rendered.iter().all(Option::is_some);
+        for ((mx, my), surface) in missing.into_iter().zip(rendered) {
+            let Some(surface) = surface else { continue };
+            for ty in 0..metatile {
+                for tx in 0..metatile {
+                    let (x, y) = (mx * metatile + tx, my * metatile + ty);
+                    let key = key(x, y);
+                    // A cached tile may already be part of the frame
+                    if self.cache.contains(&key) {
+                        continue;
+                    }
+                    let (u, v) = crop_offset(tx, ty, metatile, size, margin);
+                    let tile = crop(&surface, u, v, self.tile_size);
+                    if columns.contains(&x) && rows.contains(&y) {
+                        tiles.push((x, y, tile.clone()));
+                    }
+                    self.cache.insert(key, tile);
+                }
+            }
+        }
+        Ok(complete.then_some(tiles))
+    }
+}
+
+/// The top left corner of a `w` by `h` frame of `view`, in pixels of the grid (y up)
+fn frame_origin(view: &TiledView, w: i64, h: i64) -> (i64, i64) {
+    let upp = view.units_per_pixel;
+    let left = (view.center.x / upp - w as f64 / 2.0).round() as i64;
+    let top = (view.center.y / upp + h as f64 / 2.0).round() as i64;
+    (left, top)
+}
+
+/// The columns and rows of the tiles of `size` pixels that a frame at `left`, `top` covers
+pub(crate) fn tile_ranges(left: i64, top: i64, w: i64, h: i64, size: i64) -> (RangeInclusive<i64>, RangeInclusive<i64>) {
+    let columns = left.div_euclid(size)..=(left + w - 1).div_euclid(size);
+    let rows = (top - h).div_euclid(size)..=(top - 1).div_euclid(size);
+    (columns, rows)
+}
+
+/// The metatile a tile is part of
+pub(crate) fn metatile_of(x: i64, y: i64, metatile: i64) -> (i64, i64) {
+    (x.div_euclid(metatile), y.div_euclid(metatile))
+}
+
+/// The render of a metatile, with `margin` pixels around it
+pub(crate) fn metatile_job((mx, my): (i64, i64), metatile: i64, size: i64, margin: i64, upp: f64) -> RenderJob {
+    let meta_size = metatile * size;
+    RenderJob {
+        bbox: Box2d {
+            startx: (mx * meta_size - margin) as f64 * upp,
+            starty: (my * meta_size - margin) as f64 * upp,
+            endx: ((mx + 1) * meta_size + margin) as f64 * upp,
+            endy: ((my + 1) * meta_size + margin) as f64 * upp,
+        },
+        w: (meta_size + 2 * margin) as u32,
+        h: (meta_size + 2 * margin) as u32,
+    }
+}
+
+/// Where tile `tx`, `ty` of a metatile starts in its render. Image rows go down, grid rows go up.
+pub(crate) fn crop_offset(tx: i64, ty: i64, metatile: i64, size: i64, margin: i64) -> (i64, i64) {
+    (margin + tx * size, margin + (metatile - 1 - ty) * size)
+}
+
+/// Copy the `size` by `size` pixels at `u`, `v` of `surface` to a new surface
+pub(crate) fn crop(surface: &RenderedSurface, u: i64, v: i64, size: u32) -> RenderedSurface {
+    let tile = RenderedSurface::new(size, size);
+    unsafe {
+        cairo_surface_flush(surface.as_ptr());
+        let cr = cairo_create(tile.as_ptr());
+        cairo_set_operator(cr, _cairo_operator_CAIRO_OPERATOR_SOURCE);
+        cairo_set_source_surface(cr, surface.as_ptr(), -u as f64, -v as f64);
+        cairo_paint(cr);
+        cairo_destroy(cr);
+        cairo_surface_flush(tile.as_ptr());
+    }
+    tile
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(x: i64, y: i64) -> TileKey {
+        TileKey { generation: 0, srs: "epsg:3857".to_string(), scale: 1f64.to_bits(), x, y }
+    }
+
+    fn view(x: f64, y: f64, units_per_pixel: f64) -> TiledView {
+        TiledView { center: Point::new(x, y), units_per_pixel, srs: "epsg:3857".to_string() }
+    }
+
+    #[test]
+    fn places_the_frame_on_the_pixel_grid() {
+        assert_eq!(frame_origin(&view(100.0, 50.0, 2.0), 10, 6), (45, 28));
+        // Half pixels round away from zero
+        assert_eq!(frame_origin(&view(101.0, 0.0, 2.0), 10, 6), (46, 3));
+        assert_eq!(frame_origin(&view(-101.0, 0.0, 2.0), 10, 6), (-56, 3));
+    }
+
+    #[test]
+    fn covers_the_frame_with_tiles() {
+        assert_eq!(tile_ranges(-10, 5, 20, 10, 8), (-2..=1, -1..=0));
+        // A frame on tile boundaries doesn't need the next tile
+        assert_eq!(tile_ranges(0, 16, 16, 16, 8), (0..=1, 0..=1));
+        assert_eq!(tile_ranges(1, 16, 16, 16, 8), (0..=2, 0..=1));
+    }
+
+    #[test]
+    fn groups_tiles_in_metatiles() {
+        assert_eq!(metatile_of(0, 0, 2), (0, 0));
+        assert_eq!(metatile_of(-1, -1, 2), (-1, -1));
+        assert_eq!(metatile_of(3, -2, 2), (1, -1));
+        assert_eq!(metatile_of(-3, 5, 1), (-3, 5));
+    }
+
+    #[test]
+    fn renders_metatiles_with_a_margin() {
+        assert_eq!(metatile_job((-1, 0), 2, 256, 128, 0.5), RenderJob {
+            bbox: Box2d { startx: -320.0, starty: -64.0, endx: 64.0, endy: 320.0 },
+            w: 768,
+            h: 768,
+        });
+        assert_eq!(crop_offset(0, 0, 2, 256, 128), (128, 384));
+        assert_eq!(crop_offset(1, 1, 2, 256, 128), (384, 128));
+    }
+
+    #[test]
+    fn crops_each_tile_from_its_place_in_the_metatile() {
+        let (metatile, size, margin, upp) = (3, 16, 5, 2.0);
+        for meta in [(0, 0), (-1, 2), (4, -3)] {
+            let job = metatile_job(meta, metatile, size, margin, upp);
+            for ty in 0..metatile {
+                for tx in 0..metatile {
+                    let (x, y) = (meta.0 * metatile + tx, meta.1 * metatile + ty);
+                    assert_eq!(metatile_of(x, y, metatile), meta);
+                    let (u, v) = crop_offset(tx, ty, metatile, size, margin);
+                    assert!(u >= margin && u + size <= job.w as i64 - margin);
+                    assert!(v >= margin && v + size <= job.h as i64 - margin);
+                    // The top left corner of the crop, in pixels of the grid
+                    let left = (job.bbox.startx / upp) as i64 + u;
+                    let top = (job.bbox.endy / upp) as i64 - v;
+                    assert_eq!((left, top), (x * size, (y + 1) * size));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn drops_the_least_recently_used_tiles() {
+        let mut cache = TileCache::new(2);
+        cache.insert(key(0, 0), 0);
+        cache.insert(key(1, 0), 1);
+        assert_eq!(cache.get(&key(0, 0)), Some(0));
+        cache.insert(key(2, 0), 2);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&key(1, 0)), None);
+        assert_eq!(cache.get(&key(0, 0)), Some(0));
+        assert_eq!(cache.get(&key(2, 0)), Some(2));
+
+        // Replacing a tile doesn't drop another one
+        cache.insert(key(2, 0), 20);
+        assert_eq!(cache.get(&key(0, 0)), Some(0));
+        assert_eq!(cache.get(&key(2, 0)), Some(20));
+    }
+
+    #[test]
+    fn stays_within_its_capacity() {
+        let mut cache = TileCache::new(3);
+        for x in 0..100 {
+            cache.insert(key(x, 0), x);
+            assert!(cache.len() <= 3);
+        }
+        assert!(cache.contains(&key(99, 0)) && cache.contains(&key(98, 0)) && cache.contains(&key(97, 0)));
+        cache.clear();
+        assert!(cache.is_empty());
+        assert_eq!(TileCache::<u8>::new(0).capacity, 1);
+    }
+}